@@ -3,6 +3,8 @@ use crate::dbus_common::make_output_writer;
 use convert_case::{Case, Casing};
 
 static DBUS_VERSION: &str = "0.9.7";
+static DBUS_TOKIO_VERSION: &str = "0.7.6";
+static DBUS_CROSSROADS_VERSION: &str = "0.5.2";
 
 pub struct CrateFiles
 {
@@ -29,8 +31,20 @@ edition = "2021"
 
 [dependencies]
 dbus = "{}"
-enum_primitive = "*"
-"##, self.project_name, DBUS_VERSION)?;
+dbus-tokio = {{ version = "{}", optional = true }}
+futures-util = {{ version = "0.3", optional = true }}
+futures-channel = {{ version = "0.3", optional = true }}
+dbus-crossroads = {{ version = "{}", optional = true }}
+serde = {{ version = "1", features = ["derive"], optional = true }}
+
+[features]
+# Non-blocking AsyncInterface proxies
+async = ["dbus/futures", "dep:dbus-tokio", "dep:futures-util", "dep:futures-channel"]
+# Server traits and their registration with dbus-crossroads
+server = ["dep:dbus-crossroads"]
+# serde::Serialize and serde::Deserialize, for types generated with --derive serde or ext:derive="serde"
+serde = ["dep:serde"]
+"##, self.project_name, DBUS_VERSION, DBUS_TOKIO_VERSION, DBUS_CROSSROADS_VERSION)?;
 
         Ok(())
     }
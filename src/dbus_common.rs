@@ -3,6 +3,7 @@ extern crate xmltree;
 use clap::Parser;
 use std::{fs, io::BufWriter};
 use std::rc::Rc;
+use std::collections::HashMap;
 use std::io::Write;
 use genco::prelude::*;
 use xmltree::{AttributeName, Element};
@@ -79,6 +80,30 @@ pub struct Args {
     #[arg(long, default_value = "http://extensions.somewhere.com/schemas/dbus-extensions-v1.0")]
     pub ext_namespace: String,
 
+    /// Traits that all generated types derive where they can, e.g. Clone,PartialEq,serde
+    #[arg(long = "derive", value_delimiter = ',', default_value = "Clone")]
+    pub derives: Vec<String>,
+
+    /// Generate named structs for anonymous struct types such as a(ii), instead of tuples
+    #[arg(long)]
+    pub name_anonymous_structs: bool,
+
+    /// Interface of the methods that proxies of returned objects call to hold a reference to them
+    #[arg(long, default_value = "Zinc.DBus.RefCounted")]
+    pub refcount_interface: String,
+
+    /// Method that proxies call to take a reference, when they are cloned
+    #[arg(long, default_value = "AddRef")]
+    pub add_ref_method: String,
+
+    /// Method that proxies call to give up their reference, when they are dropped
+    #[arg(long, default_value = "DropRef")]
+    pub drop_ref_method: String,
+
+    /// Don't generate reference counting calls, unless an interface has ext:refcounted="true"
+    #[arg(long)]
+    pub no_refcount: bool,
+
     pub input_files: Vec<std::path::PathBuf>
 }
 
@@ -96,6 +121,77 @@ fn get_ext_namespace_override() -> String
     args.ext_namespace
 }
 
+fn get_default_derives() -> Vec<String>
+{
+    let args = Args::parse();
+
+    parse_derives(&args.derives.join(","))
+}
+
+fn get_name_anonymous_structs() -> bool
+{
+    let args = Args::parse();
+
+    args.name_anonymous_structs
+}
+
+fn get_default_ref_counting() -> RefCounting
+{
+    let args = Args::parse();
+
+    RefCounting {
+        enabled : ! args.no_refcount,
+        interface : args.refcount_interface,
+        add_ref : args.add_ref_method,
+        drop_ref : args.drop_ref_method
+    }
+}
+
+/// The reference counting protocol of returned objects. Proxies for objects other than
+/// the default one call add_ref when they are cloned and drop_ref when they are dropped.
+#[derive(Clone, Debug)]
+pub struct RefCounting
+{
+    pub enabled : bool,
+    pub interface : String,
+    pub add_ref : String,
+    pub drop_ref : String
+}
+
+impl RefCounting
+{
+    /// The protocol for an interface, which may override the command line defaults
+    /// with ext:refcounted, ext:refcount-interface, ext:add-ref and ext:drop-ref
+    pub fn from_interface(elem : &Element) -> RefCounting
+    {
+        let mut ref_counting = DEFAULT_REF_COUNTING.clone();
+
+        if let Some(enabled) = elem.attributes.get(&EXT_REFCOUNTED)
+        {
+            ref_counting.enabled = match enabled.as_str()
+            {
+                "true" => true,
+                "false" => false,
+                _ => panic!("ext:refcounted should be true or false, not {}", enabled)
+            };
+        }
+        if let Some(interface) = elem.attributes.get(&EXT_REFCOUNT_INTERFACE)
+        {
+            ref_counting.interface = interface.clone();
+        }
+        if let Some(add_ref) = elem.attributes.get(&EXT_ADD_REF)
+        {
+            ref_counting.add_ref = add_ref.clone();
+        }
+        if let Some(drop_ref) = elem.attributes.get(&EXT_DROP_REF)
+        {
+            ref_counting.drop_ref = drop_ref.clone();
+        }
+
+        ref_counting
+    }
+}
+
 lazy_static! {
     pub static ref EXT_TYPE_ATTRIBUTE : AttributeName = AttributeName{local_name : "type".to_string(),
      prefix : Some(get_ext_prefix_override()),
@@ -113,14 +209,132 @@ lazy_static! {
      prefix : None,
      namespace : None};
 
+    pub static ref ACCESS_ATTRIBUTE : AttributeName = AttributeName{local_name : "access".to_string(),
+     prefix : None,
+     namespace : None};
+
+    pub static ref NAMESPACE_ATTRIBUTE : AttributeName = AttributeName{local_name : "namespace".to_string(),
+     prefix : None,
+     namespace : None};
+
     pub static ref VALUE_ATTRIBUTE : AttributeName = AttributeName{local_name : "value".to_string(),
      prefix : None,
      namespace : None};
 
+    pub static ref EXT_DERIVE_ATTRIBUTE : AttributeName = AttributeName{local_name : "derive".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref DEFAULT_DERIVES : Vec<String> = get_default_derives();
+
+    pub static ref EXT_TYPE_NAME_ATTRIBUTE : AttributeName = AttributeName{local_name : "type-name".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref EXT_FIELDS_ATTRIBUTE : AttributeName = AttributeName{local_name : "fields".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref NAME_ANONYMOUS_STRUCTS : bool = get_name_anonymous_structs();
+
     pub static ref EXT_BUSNAME : AttributeName = AttributeName{local_name : "busname".to_string(),
      prefix : Some(get_ext_prefix_override()),
      namespace : Some(get_ext_namespace_override())};
 
+    pub static ref EXT_NO_DEFAULT_PATH : AttributeName = AttributeName{local_name : "no-default-path".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref EXT_BUS : AttributeName = AttributeName{local_name : "bus".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref EXT_CONSTRUCTOR : AttributeName = AttributeName{local_name : "constructor".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref DEFAULT_REF_COUNTING : RefCounting = get_default_ref_counting();
+
+    pub static ref EXT_REFCOUNTED : AttributeName = AttributeName{local_name : "refcounted".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref EXT_REFCOUNT_INTERFACE : AttributeName = AttributeName{local_name : "refcount-interface".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref EXT_ADD_REF : AttributeName = AttributeName{local_name : "add-ref".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+    pub static ref EXT_DROP_REF : AttributeName = AttributeName{local_name : "drop-ref".to_string(),
+     prefix : Some(get_ext_prefix_override()),
+     namespace : Some(get_ext_namespace_override())};
+
+}
+
+/// Traits that generated types can be asked to derive. serde derives
+/// serde::Serialize and serde::Deserialize, when the generated crate's serde
+/// feature is enabled.
+const DERIVES : [&str; 8] = ["Clone", "PartialEq", "Eq", "Hash", "PartialOrd", "Ord", "Default", "serde"];
+
+/// The traits that each user defined type derives
+pub type TypeDerives = HashMap<String, Vec<String>>;
+
+/// Parses a comma separated list of derives
+pub fn parse_derives(derives : &str) -> Vec<String>
+{
+    derives.split(',')
+        .map(|d| d.trim())
+        .filter(|d| ! d.is_empty())
+        .map(|d| {
+            if ! DERIVES.contains(&d)
+            {
+                panic!("Can't derive {}, expected one of {}", d, DERIVES.join(", "));
+            }
+            d.to_string()
+        })
+        .collect()
+}
+
+/// The derives asked for by the command line and a type's ext:derive attribute
+pub fn get_requested_derives(elem : &Element) -> Vec<String>
+{
+    let mut derives = DEFAULT_DERIVES.clone();
+
+    if let Some(ext_derives) = elem.attributes.get(&EXT_DERIVE_ATTRIBUTE)
+    {
+        for derive in parse_derives(ext_derives)
+        {
+            if ! derives.contains(&derive)
+            {
+                derives.push(derive);
+            }
+        }
+    }
+    derives
+}
+
+/// Picks the requested derives that all of a type's members allow
+pub fn get_legal_derives(requested : &[String], member_types : &[&DbusType], type_derives : &TypeDerives) -> Vec<String>
+{
+    requested.iter()
+        .filter(|derive| member_types.iter().all(|t| t.can_derive(derive, type_derives)))
+        .cloned()
+        .collect()
+}
+
+/// Generates the derive attributes of a type. Debug is always derived.
+pub fn get_derive_attributes(derives : &[String]) -> rust::Tokens
+{
+    let std_derives : Vec<&String> = derives.iter().filter(|d| *d != "serde").collect();
+
+    quote! {
+        #[derive(Debug$(for derive in std_derives => , $derive))]
+        $(if derives.iter().any(|d| d == "serde") {
+            $['\r']#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        })
+    }
 }
 
 /// Trait that the different kinds of code generator must implement
@@ -131,104 +345,53 @@ pub trait CodeGenerator {
 
     fn project_name(&self) -> &String;
 
-    fn generate(&self, output_writer : &mut BufWriter<fs::File>) -> std::io::Result<()>;
+    /// type_derives has the derives of all the user defined types
+    fn generate(&self, output_writer : &mut BufWriter<fs::File>, type_derives : &TypeDerives) -> std::io::Result<()>;
 
     fn error_types(&self) -> Vec<Rc<dyn CodeGenerator>>;
-}
 
-pub fn dbus_type_2_rust_type (dbus_type : &str)-> String
-{
-    if dbus_type == "s"
-    {
-        return "String".to_string();
-    }
-    else if dbus_type == "u"
-    {
-        return "u32".to_string();
-    }
-    else if dbus_type == "i"
-    {
-        return "i32".to_string();
+    /// The DBus type that a user defined type is sent as, if it is fixed
+    fn wire_type(&self) -> Option<DbusType> {
+        None
     }
-    else if dbus_type == "b"
-    {
-        return "bool".to_string();
-    }
-    else if dbus_type == "x"
-    {
-        return "i64".to_string();
-    }
-    else if dbus_type == "d"
-    {
-        return "f64".to_string();
-    }
-    else if dbus_type == "t"
-    {
-        return "u64".to_string();
-    }
-    else if dbus_type == "y"
-    {
-        return "u8".to_string();
-    }
-    else if dbus_type == "n"
-    {
-        return "i16".to_string();
-    }
-    else if dbus_type == "q"
-    {
-        return "u16".to_string();
+
+    /// The user defined types used by this generator, with the DBus types they are used as
+    fn ext_type_usages(&self) -> Vec<ExtTypeUsage> {
+        Vec::new()
     }
-    else if dbus_type.starts_with("(")
-    {
-        // This is a Dbus struct, represented as a tuple in Rust.
-        // Structs can be nested inside each other
-        let mut nesting_level = 0;
-        let mut rust_type = "(".to_string();
-        for c in dbus_type[1..].chars()
-        {
-            let mut b = [0; 2];
-            if c == '('
-            {
-                nesting_level += 1;
-                rust_type.push('(');
-                continue;
-            }
-            if c == ')'
-            {
-                nesting_level -= 1;
-                rust_type.push(')');
-                if nesting_level == 0
-                {
-                    return rust_type;
-                }
-                else
-                {
-                    continue;
-                }
-            }
 
-            rust_type += dbus_type_2_rust_type(c.encode_utf8(&mut b)).as_str();
-            rust_type.push(',');
-        }
-        return rust_type;
+    /// The traits that a user defined type can derive, given what the types
+    /// it contains derive. None for generators that aren't types.
+    fn derives(&self, _type_derives : &TypeDerives) -> Option<Vec<String>> {
+        None
     }
-    else if dbus_type.starts_with("a{")
-    {
-        // This is a dict
-        return "HashMap<".to_string()
-            + &dbus_type_2_rust_type(&dbus_type[2..dbus_type.len()-2])
-            + ", "
-            + &dbus_type_2_rust_type(&dbus_type[3..dbus_type.len()-1])
-            + ">";
-    }
-    else if dbus_type.starts_with('a')
-    {
-        // This is an array
-        return "Vec<".to_string() + &dbus_type_2_rust_type(&dbus_type[1..]) + ">";
+
+    /// Structs made for the anonymous structs in this generator's types,
+    /// when those are given names
+    fn anonymous_structs(&self) -> Vec<Rc<dyn CodeGenerator>> {
+        Vec::new()
     }
-    else
+}
+
+/// Maps a basic (single character) DBus type code to the Rust type used for it
+pub fn basic_type_2_rust_type (dbus_type : char) -> Option<&'static str>
+{
+    match dbus_type
     {
-        panic!("Unknown DBus type: {}", dbus_type);
+        's' => Some("String"),
+        'u' => Some("u32"),
+        'i' => Some("i32"),
+        'b' => Some("bool"),
+        'x' => Some("i64"),
+        'd' => Some("f64"),
+        't' => Some("u64"),
+        'y' => Some("u8"),
+        'n' => Some("i16"),
+        'q' => Some("u16"),
+        'o' => Some("dbus::Path<'static>"),
+        'g' => Some("dbus::Signature<'static>"),
+        'h' => Some("dbus::arg::OwnedFd"),
+        _ => None
     }
 }
 
@@ -259,13 +422,12 @@ pub fn make_lib_output_writer(path : &std::path::PathBuf, file : &str) -> std::i
     // the top of the file
     let generated_code : rust::Tokens = quote! {
         extern crate dbus;
-        #[macro_use] extern crate enum_primitive;
 
-        use crate::enum_primitive::FromPrimitive;
         use dbus::arg::*;
         use dbus::Signature;
         use dbus::blocking::Connection;
         use dbus::blocking::Proxy;
+        use dbus::blocking::stdintf::org_freedesktop_dbus::Properties as DBusProperties;
         use dbus::message::Message;
         use dbus::strings::{Path, BusName};
         use std::fmt;
@@ -273,10 +435,329 @@ pub fn make_lib_output_writer(path : &std::path::PathBuf, file : &str) -> std::i
         use std::collections::HashMap;
         use std::ops::Drop;
 
+        #[cfg(feature = "async")]
+        pub use dbus_tokio;
+        #[cfg(feature = "async")]
+        use dbus::nonblock::{MsgMatch, SyncConnection};
+        #[cfg(feature = "async")]
+        use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties as AsyncDBusProperties;
+        #[cfg(feature = "async")]
+        use dbus::message::SignalArgs;
+        #[cfg(feature = "async")]
+        use futures_util::stream::{Stream, StreamExt};
+        #[cfg(feature = "async")]
+        use std::sync::Arc;
+
+        #[cfg(feature = "server")]
+        pub use dbus_crossroads;
+        #[cfg(feature = "server")]
+        use dbus::MethodErr;
+        #[cfg(feature = "server")]
+        use dbus_crossroads::{Crossroads, IfaceBuilder, IfaceToken};
+
         static DEFAULT_TIMEOUT : Duration = Duration::from_millis(5000);
 
-        // Define a polymorphic error type
-        type Error = Box<dyn std::error::Error>;
+        /// Wraps a value in a variant, e.g. to build an a{sv} dictionary
+        pub fn variant<T : RefArg + 'static>(value : T) -> Variant<Box<dyn RefArg>> {
+            Variant(Box::new(value))
+        }
+
+        /// Extracts a value of a known type from a variant received from DBus.
+        /// Returns None if the variant holds some other type.
+        pub fn variant_value<T : for<'b> Get<'b> + Arg>(variant : &Variant<Box<dyn RefArg>>) -> Option<T> {
+            // Containers inside a variant are read as generic RefArgs, so
+            // marshal the value again and read it back as the type we want.
+            let mut message = Message::new_signal("/", "org.freedesktop.DBus", "Variant").ok()?;
+            variant.append_by_ref(&mut IterAppend::new(&mut message));
+            message.read1::<Variant<T>>().ok().map(|v| v.0)
+        }
+
+        /// Converts a file (or anything else that owns a file descriptor) into
+        /// a unix fd that can be sent over DBus
+        pub fn owned_fd<F : std::os::unix::io::IntoRawFd>(file : F) -> dbus::arg::OwnedFd {
+            // IntoRawFd hands over ownership of a valid fd, so this is sound
+            unsafe { dbus::arg::OwnedFd::new(file.into_raw_fd()) }
+        }
+
+        /// Converts a unix fd received from DBus into a File
+        pub fn fd_into_file(fd : dbus::arg::OwnedFd) -> std::fs::File {
+            use std::os::unix::io::FromRawFd;
+            // The OwnedFd gives up ownership of its fd, so this is sound
+            unsafe { std::fs::File::from_raw_fd(fd.into_fd()) }
+        }
+
+        /// Escapes a string so it can be used as one element of an object path.
+        /// Letters and digits are kept, every other byte becomes _ and two hex
+        /// digits, and the empty string becomes _.
+        pub fn escape_path_segment(segment : &str) -> String {
+            if segment.is_empty()
+            {
+                return "_".to_string();
+            }
+
+            segment.bytes().map(|b| if b.is_ascii_alphanumeric() {
+                    (b as char).to_string()
+                }
+                else {
+                    format!("_{:02x}", b)
+                })
+                .collect()
+        }
+
+        /// Calls a method and waits for its reply, which may be an error reply
+        /// with args after the message. Proxy::method_call would turn an error
+        /// reply into a dbus::Error, which only keeps the name and message.
+        /// No callbacks are run while waiting, so signals and method calls that
+        /// arrive before the reply are dropped rather than dispatched.
+        pub fn method_call_reply(connection : &Connection, msg : Message, timeout : Duration) -> Result<Message, dbus::Error> {
+            let channel = connection.channel();
+            let serial = channel.send(msg).map_err(|_| dbus::Error::new_failed("Failed to send message"))?;
+            let deadline = std::time::Instant::now() + timeout;
+
+            loop {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                if remaining.is_zero() {
+                    return Err(dbus::Error::new_custom("org.freedesktop.DBus.Error.NoReply", "Did not receive a reply"));
+                }
+
+                if let Some(reply) = channel.blocking_pop_message(remaining)? {
+                    if reply.get_reply_serial() == Some(serial) {
+                        return Ok(reply);
+                    }
+                }
+            }
+        }
+
+        /// The async version of method_call_reply. The connection hands over the
+        /// reply itself, so error replies can be read with their args.
+        #[cfg(feature = "async")]
+        pub async fn method_call_reply_async(connection : &SyncConnection, msg : Message, timeout : Duration) -> Result<Message, dbus::Error> {
+            use dbus::nonblock::NonblockReply;
+            use futures_util::future::{self, Either};
+
+            let (sender, receiver) = futures_channel::oneshot::channel();
+            let token = connection.send_with_reply(msg, SyncConnection::make_f(move |reply, _| { let _ = sender.send(reply); }))
+                .map_err(|_| dbus::Error::new_failed("Failed to send message"))?;
+
+            let timeout = match connection.timeout_maker() {
+                Some(make_timeout) => make_timeout(std::time::Instant::now() + timeout),
+                None => Box::pin(future::pending())
+            };
+
+            match future::select(receiver, timeout).await {
+                Either::Left((Ok(reply), _)) => Ok(reply),
+                Either::Left((Err(_), _)) => Err(dbus::Error::new_failed("Connection closed while waiting for the reply")),
+                Either::Right(_) => {
+                    connection.cancel_reply(token);
+                    Err(dbus::Error::new_custom("org.freedesktop.DBus.Error.Timeout", "Timeout waiting for reply"))
+                }
+            }
+        }
+
+        /// The bus's NameOwnerChanged signal, sent when a bus name gains, loses or changes owner
+        #[derive(Debug)]
+        pub struct NameOwnerChanged {
+            pub name : String,
+            /// Empty if the name had no owner
+            pub old_owner : String,
+            /// Empty if the name has no owner now, e.g. because the service exited
+            pub new_owner : String
+        }
+
+        impl ReadAll for NameOwnerChanged {
+            fn read(i: &mut Iter) -> Result<Self, TypeMismatchError> {
+                Ok(NameOwnerChanged { name : i.read()?, old_owner : i.read()?, new_owner : i.read()? })
+            }
+        }
+
+        impl dbus::message::SignalArgs for NameOwnerChanged {
+            const NAME: &'static str = "NameOwnerChanged";
+            const INTERFACE: &'static str = "org.freedesktop.DBus";
+        }
+
+        fn name_owner_changed_match() -> dbus::message::MatchRule<'static> {
+            <NameOwnerChanged as dbus::message::SignalArgs>::match_rule(Some(&"org.freedesktop.DBus".into()), None).static_clone()
+        }
+
+        /// The rule asking the bus for the owner changes of one name. MatchRule can't
+        /// match args, so arg0 is added to its rule string.
+        fn name_owner_changed_rule(bus_name : &str) -> String {
+            format!("{},arg0='{}'", name_owner_changed_match().match_str(), bus_name)
+        }
+
+        /// Waits until a bus name has an owner, e.g. for clients that may start before
+        /// the service. Other messages that arrive while waiting are handled as
+        /// Connection::process would, and the owner is checked again after each one.
+        pub fn wait_for_name(connection : &Connection, bus_name : &str, timeout : Duration) -> Result<(), dbus::Error> {
+            // Ask for the name's owner changes before checking, so the name can't be
+            // taken unnoticed in between. There is no callback for them, as a listener
+            // on the connection could be given the signal instead.
+            let rule = name_owner_changed_rule(bus_name);
+            connection.add_match_no_cb(&rule)?;
+
+            let deadline = std::time::Instant::now() + timeout;
+            let bus = connection.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout);
+            let result = loop {
+                match bus.method_call("org.freedesktop.DBus", "NameHasOwner", (bus_name,)) {
+                    Ok((true,)) => break Ok(()),
+                    Ok((false,)) => {},
+                    Err(err) => break Err(err)
+                }
+                let now = std::time::Instant::now();
+                if now >= deadline {
+                    break Err(dbus::Error::new_custom("org.freedesktop.DBus.Error.Timeout", &format!("{} has no owner", bus_name)));
+                }
+                if let Err(err) = connection.process(deadline - now) {
+                    break Err(err);
+                }
+            };
+
+            let _ = connection.remove_match_no_cb(&rule);
+            result
+        }
+
+        /// Calls back whenever a bus name changes owner, e.g. so that a client can
+        /// set up its state again after the service restarts. The listener is removed
+        /// when the callback returns false, or by passing the returned token to
+        /// remove_name_owner_changed_listener. As with other callbacks on the connection,
+        /// each signal goes to the first listener that matches it, so a connection
+        /// should only have one owner change listener at a time.
+        pub fn listen_name_owner_changed<F>(connection : &Connection, bus_name : &str, callback : F) -> Result<dbus::channel::Token, dbus::Error>
+            where F : Fn(NameOwnerChanged) -> bool + Send + 'static {
+            use dbus::channel::MatchingReceiver;
+
+            let rule = name_owner_changed_rule(bus_name);
+            connection.add_match_no_cb(&rule)?;
+
+            let bus_name = bus_name.to_string();
+            Ok(connection.start_receive(name_owner_changed_match(), Box::new(move |msg, connection| {
+                match msg.read_all::<NameOwnerChanged>() {
+                    Ok(changed) if changed.name == bus_name => {
+                        let keep = callback(changed);
+                        if ! keep {
+                            let _ = connection.remove_match_no_cb(&rule);
+                        }
+                        keep
+                    },
+                    _ => true
+                }
+            })))
+        }
+
+        /// Removes a listener added with listen_name_owner_changed
+        pub fn remove_name_owner_changed_listener(connection : &Connection, bus_name : &str, token : dbus::channel::Token) -> Result<(), dbus::Error> {
+            use dbus::channel::MatchingReceiver;
+
+            if connection.stop_receive(token).is_none() {
+                return Err(dbus::Error::new_failed("No listener with that token"));
+            }
+            connection.remove_match_no_cb(&name_owner_changed_rule(bus_name))
+        }
+
+        /// The async version of wait_for_name. It listens for the name's owner changes
+        /// while waiting, so the connection shouldn't have another owner change listener.
+        #[cfg(feature = "async")]
+        pub async fn wait_for_name_async(connection : &SyncConnection, bus_name : &str, timeout : Duration) -> Result<(), dbus::Error> {
+            use dbus::nonblock::NonblockReply;
+            use futures_util::future::{self, Either};
+
+            // Listen before asking, so the name can't be taken unnoticed in between
+            let mut changes = listen_name_owner_changed_async(connection, bus_name).await?;
+
+            let deadline = std::time::Instant::now() + timeout;
+            let bus = dbus::nonblock::Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout, connection);
+            loop {
+                let (has_owner,) : (bool,) = bus.method_call("org.freedesktop.DBus", "NameHasOwner", (bus_name,)).await?;
+                if has_owner {
+                    break Ok(());
+                }
+                let now = std::time::Instant::now();
+                if now >= deadline {
+                    break Err(dbus::Error::new_custom("org.freedesktop.DBus.Error.Timeout", &format!("{} has no owner", bus_name)));
+                }
+
+                let timeout = match connection.timeout_maker() {
+                    Some(make_timeout) => make_timeout(deadline),
+                    None => Box::pin(future::pending())
+                };
+                if let Either::Left((None, _)) = future::select(changes.next(), timeout).await {
+                    break Err(dbus::Error::new_failed("Connection closed while waiting for the name"));
+                }
+            }
+        }
+
+        /// Asks the bus to stop sending the signals of a rule. The reply isn't needed.
+        #[cfg(feature = "async")]
+        fn remove_match_message(rule : &str) -> Message {
+            Message::call_with_args("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", "RemoveMatch", (rule,))
+        }
+
+        /// The owner changes of a bus name, from listen_name_owner_changed_async.
+        /// Dropping it removes the listener.
+        #[cfg(feature = "async")]
+        pub struct NameOwnerChanges<'a> {
+            connection : &'a SyncConnection,
+            token : dbus::channel::Token,
+            rule : String,
+            receiver : futures_channel::mpsc::UnboundedReceiver<NameOwnerChanged>
+        }
+
+        #[cfg(feature = "async")]
+        impl Stream for NameOwnerChanges<'_> {
+            type Item = NameOwnerChanged;
+
+            fn poll_next(mut self : std::pin::Pin<&mut Self>, cx : &mut std::task::Context<'_>) -> std::task::Poll<Option<NameOwnerChanged>> {
+                self.receiver.poll_next_unpin(cx)
+            }
+        }
+
+        #[cfg(feature = "async")]
+        impl Drop for NameOwnerChanges<'_> {
+            fn drop(&mut self) {
+                use dbus::channel::{MatchingReceiver, Sender};
+
+                // The listener isn't on the connection while it is being called, so closing
+                // the channel first makes it remove itself in that case
+                self.receiver.close();
+                if self.connection.stop_receive(self.token).is_some() {
+                    let _ = self.connection.send(remove_match_message(&self.rule));
+                }
+            }
+        }
+
+        /// The async version of listen_name_owner_changed, which gives the changes as
+        /// a stream. The listener is removed when the stream is dropped, and the same
+        /// one listener per connection applies.
+        #[cfg(feature = "async")]
+        pub async fn listen_name_owner_changed_async<'a>(connection : &'a SyncConnection, bus_name : &str) -> Result<NameOwnerChanges<'a>, dbus::Error> {
+            use dbus::channel::{MatchingReceiver, Sender};
+
+            let rule = name_owner_changed_rule(bus_name);
+            connection.add_match_no_cb(&rule).await?;
+
+            let (sender, receiver) = futures_channel::mpsc::unbounded();
+            let bus_name = bus_name.to_string();
+            let filter_rule = rule.clone();
+            let token = connection.start_receive(name_owner_changed_match(), Box::new(move |msg, connection| {
+                match msg.read_all::<NameOwnerChanged>() {
+                    Ok(changed) if changed.name == bus_name => {
+                        let keep = sender.unbounded_send(changed).is_ok();
+                        if ! keep {
+                            // The stream was dropped while this was being called
+                            let _ = connection.send(remove_match_message(&filter_rule));
+                        }
+                        keep
+                    },
+                    _ => true
+                }
+            }));
+            Ok(NameOwnerChanges { connection, token, rule, receiver })
+        }
+
+        /// Looks up a key in an a{sv} dictionary and extracts its value as a known type
+        pub fn prop_value<T : for<'b> Get<'b> + Arg>(props : &HashMap<String, Variant<Box<dyn RefArg>>>, key : &str) -> Option<T> {
+            variant_value(props.get(key)?)
+        }
 
 
     };
@@ -287,50 +768,197 @@ pub fn make_lib_output_writer(path : &std::path::PathBuf, file : &str) -> std::i
     Ok(output_writer)
 }
 
-pub fn remove_square_brackets(ext_type : &str) -> &str
+/// A node in the type tree built from a DBus signature (or from an ext:type,
+/// which is a DBus signature that may also contain user defined `[Name]` types)
+#[derive(Clone, Debug, PartialEq)]
+pub enum DbusType
 {
-    if ext_type.starts_with('[')
+    /// A single character type such as `s` or `u`
+    Basic(char),
+    /// `a` followed by any single complete type
+    Array(Box<DbusType>),
+    /// `a{..}`. The key is always a basic (or user defined enum) type
+    Dict(Box<DbusType>, Box<DbusType>),
+    /// `(..)`, represented as a tuple in Rust
+    Struct(Vec<DbusType>),
+    /// `v`
+    Variant,
+    /// A user defined type from an ext:type, e.g. `[Struct15]`
+    Ext(String),
+    /// An object path (`o`) to an object implementing the named interface.
+    /// Methods returning one of these give back a proxy for that interface.
+    ReturnedObject(String)
+}
+
+impl DbusType {
+
+    /// Returns the Rust type used for this DBus type in generated code
+    pub fn get_type_decl(&self) -> String
     {
-        return &ext_type[1..(ext_type.len() - 1)];
+        match self
+        {
+            DbusType::Basic(c) => basic_type_2_rust_type(*c).unwrap().to_string(),
+            DbusType::Array(element) => format!("Vec<{}>", element.get_type_decl()),
+            DbusType::Dict(key, value) => format!("HashMap<{}, {}>", key.get_type_decl(), value.get_type_decl()),
+            DbusType::Struct(members) if members.len() == 1 => format!("({},)", members[0].get_type_decl()),
+            DbusType::Struct(members) => {
+                let member_decls : Vec<String> = members.iter().map(|m| m.get_type_decl()).collect();
+                format!("({})", member_decls.join(", "))
+            },
+            DbusType::Variant => "Variant<Box<dyn RefArg>>".to_string(),
+            DbusType::Ext(name) => name.clone(),
+            DbusType::ReturnedObject(_) => "dbus::Path<'static>".to_string()
+        }
     }
-    else {
-        return ext_type;
+
+    /// Returns the DBus signature of this type, with user defined types as `[Name]`
+    pub fn get_signature(&self) -> String
+    {
+        match self
+        {
+            DbusType::Basic(c) => c.to_string(),
+            DbusType::Array(element) => format!("a{}", element.get_signature()),
+            DbusType::Dict(key, value) => format!("a{{{}{}}}", key.get_signature(), value.get_signature()),
+            DbusType::Struct(members) => format!("({})", members.iter().map(|m| m.get_signature()).collect::<String>()),
+            DbusType::Variant => "v".to_string(),
+            DbusType::Ext(name) => format!("[{}]", name),
+            DbusType::ReturnedObject(_) => "o".to_string()
+        }
+    }
+
+    /// Returns true if a type containing this one can derive the trait.
+    /// Variants hold a boxed RefArg, so types containing them can't derive anything.
+    pub fn can_derive(&self, derive : &str, type_derives : &TypeDerives) -> bool
+    {
+        match self
+        {
+            // f64 and OwnedFd aren't Eq, OwnedFd and Signature have no default,
+            // and the dbus string types don't implement serde
+            DbusType::Basic(c) => ! matches!((derive, c),
+                ("Eq" | "Hash" | "Ord", 'd' | 'h') | ("Default", 'g' | 'h') | ("serde", 'o' | 'g' | 'h')),
+            DbusType::Array(element) => element.can_derive(derive, type_derives),
+            DbusType::Dict(key, value) => ! matches!(derive, "Hash" | "PartialOrd" | "Ord")
+                && key.can_derive(derive, type_derives) && value.can_derive(derive, type_derives),
+            DbusType::Struct(members) => members.iter().all(|m| m.can_derive(derive, type_derives)),
+            DbusType::Variant => false,
+            DbusType::Ext(name) => type_derives.get(name).is_none_or(|derives| derives.iter().any(|d| d == derive)),
+            DbusType::ReturnedObject(_) => derive != "serde"
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct DbusType
+/// Recursive descent parser for DBus signatures.
+/// See https://dbus.freedesktop.org/doc/dbus-specification.html#message-protocol-signatures
+struct SignatureParser<'a>
 {
-    pub type_name : String,
-    pub contained_types : Vec<DbusType>,
-    pub is_returned_object : bool
+    signature : &'a str,
+    pos : usize
 }
 
-impl DbusType {
+impl<'a> SignatureParser<'a> {
 
-    pub fn get_type_decl(&self) -> String
+    fn peek(&self) -> Option<char>
     {
-        let mut type_decl = self.type_name.clone();
+        self.signature[self.pos..].chars().next()
+    }
 
-        if !self.contained_types.is_empty()
+    fn next(&mut self) -> Result<char, String>
+    {
+        match self.peek()
         {
-            type_decl += "<";
-            let mut i = 0;
-            while i < self.contained_types.len()
-            {
-                type_decl += &self.contained_types[i].get_type_decl();
-                if i < self.contained_types.len() - 1
+            Some(c) => {
+                self.pos += c.len_utf8();
+                Ok(c)
+            },
+            None => Err(format!("Unexpected end of DBus type {}", self.signature))
+        }
+    }
+
+    fn expect(&mut self, expected : char) -> Result<(), String>
+    {
+        let c = self.next()?;
+        if c == expected
+        {
+            Ok(())
+        }
+        else
+        {
+            Err(format!("Expected '{}' but found '{}' at position {} of DBus type {}",
+                expected, c, self.pos - 1, self.signature))
+        }
+    }
+
+    /// Parses a single complete type starting at the current position
+    fn parse_complete_type(&mut self) -> Result<DbusType, String>
+    {
+        let start = self.pos;
+        match self.next()?
+        {
+            'a' => {
+                if self.peek() == Some('{')
+                {
+                    self.pos += 1;
+                    let key = self.parse_complete_type()?;
+                    if ! matches!(key, DbusType::Basic(_) | DbusType::Ext(_))
+                    {
+                        return Err(format!("Dict key at position {} of DBus type {} is not a basic type",
+                            start + 2, self.signature));
+                    }
+                    let value = self.parse_complete_type()?;
+                    self.expect('}')?;
+                    Ok(DbusType::Dict(Box::new(key), Box::new(value)))
+                }
+                else
                 {
-                    type_decl += ", ";
+                    Ok(DbusType::Array(Box::new(self.parse_complete_type()?)))
                 }
-                i += 1;
-            }
-            type_decl += ">";
+            },
+            '(' => {
+                let mut members = Vec::new();
+                while self.peek() != Some(')')
+                {
+                    members.push(self.parse_complete_type()?);
+                }
+                self.pos += 1;
+                if members.is_empty()
+                {
+                    return Err(format!("Empty struct at position {} of DBus type {}", start, self.signature));
+                }
+                Ok(DbusType::Struct(members))
+            },
+            '[' => {
+                // A user defined type name, only valid in an ext:type
+                match self.signature[self.pos..].find(']')
+                {
+                    Some(len) if len > 0 => {
+                        let name = self.signature[self.pos..self.pos + len].to_string();
+                        self.pos += len + 1;
+                        Ok(DbusType::Ext(name))
+                    },
+                    _ => Err(format!("Unterminated type name at position {} of DBus type {}", start, self.signature))
+                }
+            },
+            'v' => Ok(DbusType::Variant),
+            c if basic_type_2_rust_type(c).is_some() => Ok(DbusType::Basic(c)),
+            c => Err(format!("Unknown type code '{}' at position {} of DBus type {}", c, start, self.signature))
         }
-        type_decl
     }
 }
 
+/// Parses a DBus signature holding exactly one complete type into a type tree
+pub fn parse_dbus_type(signature : &str) -> Result<DbusType, String>
+{
+    let mut parser = SignatureParser { signature, pos : 0 };
+    let dbus_type = parser.parse_complete_type()?;
+
+    if parser.pos != signature.len()
+    {
+        return Err(format!("Unexpected '{}' after complete type in DBus type {}",
+            &signature[parser.pos..], signature));
+    }
+    Ok(dbus_type)
+}
+
 pub struct DbusMethodArg {
     pub name : String,
     pub arg_type : DbusType
@@ -340,76 +968,108 @@ impl DbusMethodArg {
 
     pub fn get_arg_declaration(&self) -> rust::Tokens
     {
-        quote!{ $(&self.name) : &$(&self.arg_type.type_name) }
+        quote!{ $(&self.name) : &$(self.arg_type.get_type_decl()) }
     }
-}
 
-pub fn get_dbus_type(elem : &Element) -> DbusType
-{
-    if let Some(ext_type) = elem.attributes.get(&EXT_TYPE_ATTRIBUTE)
+    /// The name given in introspection data, without any raw identifier prefix.
+    /// Unnamed args are called arg0, arg1 and so on from their position.
+    pub fn get_introspection_name(&self, index : usize) -> String
     {
-        let is_returned_object = elem.attributes.get(&TYPE_ATTRIBUTE).unwrap() == "o";
-        // This is a map
-        if ext_type.starts_with("a{")
+        match self.name.trim_start_matches("r#")
         {
-            let mut contained_types = Vec::new();
-            let mut i = 2;
+            "" => format!("arg{}", index),
+            name => name.to_string()
+        }
+    }
+}
 
-            while i < ext_type.len()
+/// A use of a user defined type in an ext:type, with the part of the DBus
+/// type that it corresponds to
+#[derive(Clone)]
+pub struct ExtTypeUsage {
+    pub name : String,
+    pub wire_type : DbusType,
+    /// Where the type is used, for error messages
+    pub context : String
+}
+
+/// Walks an ext:type and the DBus type it extends together, recording what
+/// each user defined type corresponds to. Fails if they have different shapes.
+fn collect_ext_type_usages(ext_type : &DbusType, wire_type : &DbusType, context : &str, usages : &mut Vec<ExtTypeUsage>) -> Result<(), String>
+{
+    match (ext_type, wire_type)
+    {
+        (DbusType::Ext(name), _) => usages.push(ExtTypeUsage {
+            name : name.clone(),
+            wire_type : wire_type.clone(),
+            context : context.to_string() }),
+        (DbusType::Array(element), DbusType::Array(wire_element)) =>
+            collect_ext_type_usages(element, wire_element, context, usages)?,
+        (DbusType::Dict(key, value), DbusType::Dict(wire_key, wire_value)) => {
+            collect_ext_type_usages(key, wire_key, context, usages)?;
+            collect_ext_type_usages(value, wire_value, context, usages)?;
+        },
+        (DbusType::Struct(members), DbusType::Struct(wire_members)) if members.len() == wire_members.len() => {
+            for (member, wire_member) in members.iter().zip(wire_members)
             {
-                if let Some(c) = ext_type.chars().nth(i)
-                {
-                    if c == '['
-                    {
-                        if let Some(mut close_bracket) = ext_type.as_str()[i..].find(']')
-                        {
-                            close_bracket += i;
-                            i += 1;
-                            contained_types.push(DbusType { type_name: ext_type.as_str()[i..close_bracket].to_string(),
-                                contained_types: Vec::new(),
-                                is_returned_object
-                            });
-                            i = close_bracket;
-                        }
-                    }
-                    else if c != '}'
-                    {
-                        contained_types.push(DbusType { type_name: dbus_type_2_rust_type(&String::from(c)),
-                            contained_types: Vec::new(),
-                            is_returned_object
-                        });
-                    }
-                }
-                i += 1;
+                collect_ext_type_usages(member, wire_member, context, usages)?;
             }
+        },
+        (DbusType::Struct(members), DbusType::Struct(wire_members)) =>
+            return Err(format!("struct {} has {} members but {} has {}",
+                ext_type.get_signature(), members.len(), wire_type.get_signature(), wire_members.len())),
+        (DbusType::Basic(c), DbusType::Basic(wire_c)) if c == wire_c => (),
+        (DbusType::Variant, DbusType::Variant) => (),
+        _ => return Err(format!("{} doesn't match {}", ext_type.get_signature(), wire_type.get_signature()))
+    }
+    Ok(())
+}
 
-            return DbusType{ type_name: "HashMap".to_string(),
-                             contained_types,
-                             is_returned_object};
-        }
-        else if ext_type.starts_with("a")
+/// Returns the user defined types used in an element's ext:type. `parent`
+/// names what the element belongs to for the error message if the ext:type
+/// doesn't have the same shape as the DBus type.
+pub fn get_ext_type_usages(elem : &Element, parent : &str) -> Vec<ExtTypeUsage>
+{
+    let mut usages = Vec::new();
+
+    if let Some(ext_type_str) = elem.attributes.get(&EXT_TYPE_ATTRIBUTE)
+    {
+        let signature = elem.attributes.get(&TYPE_ATTRIBUTE).unwrap();
+        let context = format!("ext:type {} with type {} of {}", ext_type_str, signature, describe_element(elem, parent));
+
+        let result = parse_dbus_type(ext_type_str)
+            .and_then(|ext_type| Ok((ext_type, parse_dbus_type(signature)?)))
+            .and_then(|(ext_type, wire_type)| collect_ext_type_usages(&ext_type, &wire_type, &context, &mut usages));
+
+        if let Err(err) = result
         {
-            // This is an array
-            return DbusType{ type_name: "Vec".to_string(),
-                contained_types: vec![DbusType{
-                    type_name: remove_square_brackets(&ext_type[1..]).to_string(),
-                    contained_types: Vec::new(),
-                is_returned_object}],
-                is_returned_object
-            };
+            panic!("Bad {}: {}", context, err);
         }
-        // This is a regular extension type
-        DbusType{ type_name: remove_square_brackets(ext_type).to_string(), contained_types: Vec::new(), is_returned_object }
     }
-    else
+    usages
+}
+
+/// Names an element for error messages, e.g. `arg handle of method Method1 of interface a.b.C`
+pub fn describe_element(elem : &Element, parent : &str) -> String
+{
+    format!("{} {} of {}", elem.name,
+        elem.attributes.get(&NAME_ATTRIBUTE).map(|n| n.as_str()).unwrap_or("(unnamed)"), parent)
+}
+
+/// Returns the type of an arg, member or property. `parent` names what the
+/// element belongs to for the error message if the type can't be parsed.
+pub fn get_dbus_type(elem : &Element, parent : &str) -> DbusType
+{
+    let signature = elem.attributes.get(&TYPE_ATTRIBUTE).unwrap();
+    let type_str = elem.attributes.get(&EXT_TYPE_ATTRIBUTE).unwrap_or(signature);
+
+    match parse_dbus_type(type_str)
     {
-        DbusType{
-            type_name: dbus_type_2_rust_type(elem.attributes.get(&TYPE_ATTRIBUTE).unwrap()).to_string(),
-            contained_types: Vec::new(),
-            is_returned_object: false
-        }
+        // An object path with an extension type is an object implementing that interface
+        Ok(DbusType::Ext(name)) if signature == "o" => DbusType::ReturnedObject(name),
+        Ok(dbus_type) => dbus_type,
+        Err(err) => panic!("Bad type for {}: {}", describe_element(elem, parent), err)
     }
-
 }
 
 pub fn prefix_keywords(identifier : &str) -> String
@@ -418,3 +1078,113 @@ pub fn prefix_keywords(identifier : &str) -> String
     if RUST_KEYWORDS.iter().any(|i| i == &r) { r.push('_') };
     r
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(c : char) -> DbusType
+    {
+        DbusType::Basic(c)
+    }
+
+    fn array(element : DbusType) -> DbusType
+    {
+        DbusType::Array(Box::new(element))
+    }
+
+    fn dict(key : DbusType, value : DbusType) -> DbusType
+    {
+        DbusType::Dict(Box::new(key), Box::new(value))
+    }
+
+    #[test]
+    fn parses_basic_types()
+    {
+        assert_eq!(parse_dbus_type("s"), Ok(basic('s')));
+        assert_eq!(parse_dbus_type("v"), Ok(DbusType::Variant));
+        assert_eq!(parse_dbus_type("[Enum0]"), Ok(DbusType::Ext("Enum0".to_string())));
+    }
+
+    #[test]
+    fn parses_nested_containers()
+    {
+        assert_eq!(parse_dbus_type("a{sa(ii)}"),
+            Ok(dict(basic('s'), array(DbusType::Struct(vec![basic('i'), basic('i')])))));
+        assert_eq!(parse_dbus_type("aa{sv}"), Ok(array(dict(basic('s'), DbusType::Variant))));
+        assert_eq!(parse_dbus_type("a(sa{su})"),
+            Ok(array(DbusType::Struct(vec![basic('s'), dict(basic('s'), basic('u'))]))));
+        assert_eq!(parse_dbus_type("(ia{s(ib)}a{[Enum0]v})"),
+            Ok(DbusType::Struct(vec![
+                basic('i'),
+                dict(basic('s'), DbusType::Struct(vec![basic('i'), basic('b')])),
+                dict(DbusType::Ext("Enum0".to_string()), DbusType::Variant)])));
+    }
+
+    #[test]
+    fn signatures_round_trip()
+    {
+        for signature in ["a{sa(ii)}", "aa{sv}", "a(sa{su})", "(ia{s(ib)}a{[Enum0]v})"]
+        {
+            assert_eq!(parse_dbus_type(signature).unwrap().get_signature(), signature);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_signatures()
+    {
+        for signature in [
+            "",          // no type
+            "(ii",       // unbalanced struct
+            "a(ii",      // unbalanced struct in an array
+            "ii)",       // struct end without a start
+            "()",        // empty struct
+            "{sv}",      // dict outside an array
+            "a{sv",      // unterminated dict
+            "a{svs}",    // dict with more than a key and a value
+            "a{(i)s}",   // struct dict key
+            "a{vs}",     // variant dict key
+            "a{asi}",    // array dict key
+            "a",         // array without an element type
+            "ii",        // trailing characters
+            "a{sv}z",    // unknown trailing type
+            "[Enum0",    // unterminated type name
+            "[]",        // empty type name
+            "z"          // unknown type code
+        ]
+        {
+            assert!(parse_dbus_type(signature).is_err(), "{} should not parse", signature);
+        }
+    }
+
+    fn ext_usages(ext_type : &str, wire_type : &str) -> Result<Vec<(String, String)>, String>
+    {
+        let mut usages = Vec::new();
+        collect_ext_type_usages(&parse_dbus_type(ext_type).unwrap(), &parse_dbus_type(wire_type).unwrap(), "test", &mut usages)?;
+        Ok(usages.into_iter().map(|usage| (usage.name, usage.wire_type.get_signature())).collect())
+    }
+
+    #[test]
+    fn ext_types_match_their_wire_types()
+    {
+        assert_eq!(ext_usages("a{[Enum0]a([Struct1]s)}", "a{ia((ii)s)}"),
+            Ok(vec![("Enum0".to_string(), "i".to_string()), ("Struct1".to_string(), "(ii)".to_string())]));
+    }
+
+    #[test]
+    fn rejects_ext_types_of_another_shape()
+    {
+        for (ext_type, wire_type) in [
+            ("a[Enum0]", "i"),          // array over a basic type
+            ("a{s[Enum0]}", "aai"),     // dict over an array
+            ("a[Enum0]", "a{si}"),      // array over a dict
+            ("([Enum0]s)", "(isi)"),    // fewer struct members
+            ("([Enum0]ss)", "(is)"),    // more struct members
+            ("(s[Enum0])", "(ui)"),     // different basic type
+            ("v", "s")
+        ]
+        {
+            assert!(ext_usages(ext_type, wire_type).is_err(), "{} shouldn't match {}", ext_type, wire_type);
+        }
+    }
+}
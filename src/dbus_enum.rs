@@ -14,6 +14,31 @@ pub struct DbusEnum {
     project_name : String,
     names : Vec<String>,
     values : Vec<String>,
+    /// The integer (or, except for flags, string) DBus type the enum is sent as.
+    /// Defaults to i, or u for flags.
+    wire_type : char,
+    /// Generated from an ext:flags element. The values are bits that can be
+    /// combined, rather than alternatives.
+    flags : bool,
+    /// Derives asked for on top of the ones enums always have
+    requested_derives : Vec<String>
+}
+
+/// Maps a DBus type code that enums can be sent as to its dbus::arg::ArgType
+fn enum_arg_type(dbus_type : char) -> Option<&'static str>
+{
+    match dbus_type
+    {
+        's' => Some("String"),
+        'y' => Some("Byte"),
+        'n' => Some("Int16"),
+        'q' => Some("UInt16"),
+        'i' => Some("Int32"),
+        'u' => Some("UInt32"),
+        'x' => Some("Int64"),
+        't' => Some("UInt64"),
+        _ => None
+    }
 }
 
 impl DbusEnum {
@@ -25,6 +50,17 @@ impl DbusEnum {
             let tokens : Vec<&str> = name_str.rsplit(".").collect();
             let mut names : Vec<String> = Vec::new();
             let mut values : Vec<String> = Vec::new();
+            let flags = elem.name == "flags";
+            let requested_derives = get_requested_derives(elem);
+
+            let wire_type = match elem.attributes.get(&TYPE_ATTRIBUTE).map(|t| t.as_str())
+            {
+                None if flags => 'u',
+                None => 'i',
+                Some("s") if flags => panic!("Flags {} can't have type s", name_str),
+                Some(t) if t.len() == 1 && enum_arg_type(t.chars().next().unwrap()).is_some() => t.chars().next().unwrap(),
+                Some(t) => panic!("Enum {} has type {}, which is not an integer or string DBus type", name_str, t)
+            };
 
             while let Some(child) = elem.take_child("enumvalue")
             {
@@ -35,7 +71,10 @@ impl DbusEnum {
             Ok(DbusEnum { name : tokens[0].to_string(),
                 project_name : tokens[1].to_string(),
                 names,
-                values})
+                values,
+                wire_type,
+                flags,
+                requested_derives})
         }
         else
         {
@@ -47,66 +86,269 @@ impl DbusEnum {
 
 impl CodeGenerator for DbusEnum {
 
-    fn generate(&self, output_writer : &mut BufWriter<File>) -> std::io::Result<()>{
+    fn generate(&self, output_writer : &mut BufWriter<File>, type_derives : &TypeDerives) -> std::io::Result<()>{
 
         let enum_name = &self.name;
         let enum_names = &self.names;
         let enum_values = &self.values;
+        let rust_type = basic_type_2_rust_type(self.wire_type).unwrap();
+        let arg_type = enum_arg_type(self.wire_type).unwrap();
+        let is_string = self.wire_type == 's';
+        // String enums are made from a &str, and give one back
+        let value_type = if is_string { "&str" } else { rust_type };
+        let mut derives = self.derives(type_derives).unwrap();
+        if ! is_string
+        {
+            derives.insert(1, "Copy".to_string());
+        }
+        // The first value is the default
+        let default_first = ! self.flags && derives.iter().any(|d| d == "Default");
+        // Enums are compared by value, so that Unknown or Other holding a known value
+        // is the same as the named variant. They are ordered by value too, to agree.
+        let compared_by_value = ! self.flags;
+        let partial_ord = compared_by_value && derives.iter().any(|d| d == "PartialOrd");
+        let ord = compared_by_value && derives.iter().any(|d| d == "Ord");
+        if compared_by_value
+        {
+            derives.retain(|d| ! matches!(d.as_str(), "PartialEq" | "Eq" | "Hash" | "PartialOrd" | "Ord"));
+        }
 
         // See https://docs.rs/genco/0.17.2/genco/macro.quote.html
-        let generated_code : rust::Tokens = quote! {
+        let type_code : rust::Tokens = if self.flags
+        {
+            quote! {
+                // A set of flags. Bits that this version of the interface doesn't
+                // know about are kept, so the value can be sent back unchanged.
+                $(get_derive_attributes(&derives))
+                pub struct $enum_name(pub $rust_type);
+
+                impl $enum_name
+                {
+                    $(for (n, v) in enum_names.iter().zip(enum_values) => $['\r']pub const $n : $enum_name = $enum_name($v);)
+
+                    // All the flags that this version of the interface knows about
+                    pub const ALL : $enum_name = $enum_name(0 $(for v in enum_values => | $v));
+
+                    pub fn new(val : $rust_type) -> Self
+                    {
+                        $enum_name(val)
+                    }
+
+                    pub fn value(&self) -> $rust_type
+                    {
+                        self.0
+                    }
+
+                    pub fn empty() -> Self
+                    {
+                        $enum_name(0)
+                    }
+
+                    pub fn is_empty(&self) -> bool
+                    {
+                        self.0 == 0
+                    }
+
+                    pub fn contains(&self, other : $enum_name) -> bool
+                    {
+                        self.0 & other.0 == other.0
+                    }
+
+                    pub fn insert(&mut self, other : $enum_name)
+                    {
+                        self.0 |= other.0;
+                    }
+
+                    pub fn remove(&mut self, other : $enum_name)
+                    {
+                        self.0 &= !other.0;
+                    }
+
+                    // The set bits that aren't known flags
+                    pub fn unknown_bits(&self) -> $rust_type
+                    {
+                        self.0 & !$enum_name::ALL.0
+                    }
+
+                    // The known flags that are set. Flags with no bits, such as a NONE
+                    // value, are never counted as set.
+                    pub fn iter(&self) -> impl Iterator<Item = $enum_name> + '_
+                    {
+                        [$(for n in enum_names join(, ) => $enum_name::$n)].into_iter().filter(move |flag| flag.0 != 0 && self.contains(*flag))
+                    }
+                }
+
+                impl std::ops::BitOr for $enum_name {
+                    type Output = Self;
 
-            enum_from_primitive! {
-            #[derive(Clone, Copy, Debug)]
-            pub enum $enum_name {
-                $(for (n, v) in enum_names.into_iter().zip(enum_values) join(, ) => $['\r']$n = $v)
+                    fn bitor(self, other : Self) -> Self {
+                        $enum_name(self.0 | other.0)
+                    }
+                }
+
+                impl std::ops::BitOrAssign for $enum_name {
+                    fn bitor_assign(&mut self, other : Self) {
+                        self.0 |= other.0;
+                    }
+                }
+
+                impl std::ops::BitAnd for $enum_name {
+                    type Output = Self;
+
+                    fn bitand(self, other : Self) -> Self {
+                        $enum_name(self.0 & other.0)
+                    }
+                }
+
+                impl std::ops::BitAndAssign for $enum_name {
+                    fn bitand_assign(&mut self, other : Self) {
+                        self.0 &= other.0;
+                    }
+                }
             }
+        }
+        else if is_string
+        {
+            quote! {
+                $(get_derive_attributes(&derives))
+                pub enum $enum_name {
+                    $(for (i, n) in enum_names.iter().enumerate() => $['\r']$(if i == 0 && default_first => #[default]) $n,)
+                    /// A value that this version of the interface doesn't know about.
+                    /// new and From give the named variant for known values.
+                    Other(String)
+                }
+
+                impl $enum_name
+                {
+                    pub fn new(val : &str) -> Self
+                    {
+                        match val {
+                            $(for (n, v) in enum_names.iter().zip(enum_values) => $['\r']$(quoted(v)) => $enum_name::$n,)
+                            _ => $enum_name::Other(val.to_string())
+                        }
+                    }
+
+                    pub fn value(&self) -> &str
+                    {
+                        match self {
+                            $(for (n, v) in enum_names.iter().zip(enum_values) => $['\r']$enum_name::$n => $(quoted(v)),)
+                            $enum_name::Other(val) => val
+                        }
+                    }
+                }
             }
+        }
+        else
+        {
+            quote! {
+                $(get_derive_attributes(&derives))
+                pub enum $enum_name {
+                    $(for (i, n) in enum_names.iter().enumerate() => $['\r']$(if i == 0 && default_first => #[default]) $n,)
+                    /// A value that this version of the interface doesn't know about.
+                    /// new and From give the named variant for known values.
+                    Unknown($rust_type)
+                }
 
-            impl $enum_name
-            {
-                pub fn new(val : i32) -> Self
+                impl $enum_name
                 {
-                    // TODO The .unwrap() will terminate the process if we get invalid enum values
-                    // from DBus :-S
-                    $enum_name::from_i32(val).unwrap()
+                    pub fn new(val : $rust_type) -> Self
+                    {
+                        match val {
+                            $(for (n, v) in enum_names.iter().zip(enum_values) => $['\r']$v => $enum_name::$n,)
+                            _ => $enum_name::Unknown(val)
+                        }
+                    }
+
+                    pub fn value(&self) -> $rust_type
+                    {
+                        match self {
+                            $(for (n, v) in enum_names.iter().zip(enum_values) => $['\r']$enum_name::$n => $v,)
+                            $enum_name::Unknown(val) => *val
+                        }
+                    }
                 }
             }
+        };
+
+        let generated_code : rust::Tokens = quote! {
+            $type_code
 
+            $(if compared_by_value {
+                impl PartialEq for $enum_name {
+                    fn eq(&self, other : &Self) -> bool {
+                        self.value() == other.value()
+                    }
+                }
+
+                impl Eq for $enum_name {}
+
+                impl std::hash::Hash for $enum_name {
+                    fn hash<H : std::hash::Hasher>(&self, state : &mut H) {
+                        self.value().hash(state);
+                    }
+                }
+            })
+
+            $(if ord {
+                impl Ord for $enum_name {
+                    fn cmp(&self, other : &Self) -> std::cmp::Ordering {
+                        self.value().cmp(&other.value())
+                    }
+                }
+            })
+
+            $(if partial_ord {
+                impl PartialOrd for $enum_name {
+                    fn partial_cmp(&self, other : &Self) -> Option<std::cmp::Ordering> {
+                        $(if ord {
+                            Some(self.cmp(other))
+                        } else {
+                            Some(self.value().cmp(&other.value()))
+                        })
+                    }
+                }
+            })
+
+            impl From<$value_type> for $enum_name {
+                fn from(val : $value_type) -> Self {
+                    $enum_name::new(val)
+                }
+            }
+
+            impl From<$enum_name> for $rust_type {
+                fn from(val : $enum_name) -> Self {
+                    val.value()$(if is_string => .to_string())
+                }
+            }
 
             impl dbus::arg::ReadAll for $enum_name {
                 fn read(i: &mut dbus::arg::Iter) -> Result<Self, dbus::arg::TypeMismatchError> {
-                    Ok($enum_name::from_i32(i.read().unwrap()).unwrap())
+                    Ok($enum_name::new(i.read()?))
                 }
             }
 
             impl<'a> dbus::arg::Get<'a> for $enum_name {
                 fn get(i: &mut dbus::arg::Iter<'a>) -> Option<Self> {
-                    match i.get()
-                    {
-                        Some(value) => $enum_name::from_i32(value),
-                        None => None
-                    }
+                    i.get().map($enum_name::new)
                 }
             }
 
             impl dbus::arg::Append for $enum_name {
                 fn append_by_ref(&self, i: &mut IterAppend<'_>) {
-                    i.append(*self as i32);
-                }
-
-                fn append(self, i: &mut IterAppend<'_>) {
-                    i.append(self as i32);
+                    i.append(self.value());
                 }
             }
-            
+
             impl dbus::arg::Arg for $enum_name {
-                const ARG_TYPE : ArgType = ArgType::Int32;
+                const ARG_TYPE : ArgType = ArgType::$arg_type;
 
                 fn signature() -> Signature<'static> {
-                    <i32 as dbus::arg::Arg>::signature()
+                    <$rust_type as dbus::arg::Arg>::signature()
                 }
             }
+
+            // Enums are sent as basic types, so they can be dictionary keys
+            impl dbus::arg::DictKey for $enum_name {}
         };
 
         let generated_string = generated_code.to_file_string().unwrap();
@@ -128,4 +370,27 @@ impl CodeGenerator for DbusEnum {
     fn error_types(&self) -> Vec<std::rc::Rc<dyn CodeGenerator>> {
         Vec::new()
     }
+
+    fn wire_type(&self) -> Option<DbusType> {
+        Some(DbusType::Basic(self.wire_type))
+    }
+
+    fn derives(&self, _type_derives : &TypeDerives) -> Option<Vec<String>> {
+        // Enums can derive anything, apart from Default when there's no value
+        // to use as the default. Flags default to no flags set.
+        let mut derives : Vec<String> = ["Clone", "PartialEq", "Eq", "Hash"].iter().map(|d| d.to_string()).collect();
+
+        for derive in &self.requested_derives
+        {
+            if ! derives.contains(derive) && (derive != "Default" || self.flags || ! self.names.is_empty())
+            {
+                derives.push(derive.clone());
+            }
+        }
+        if self.flags && ! derives.iter().any(|d| d == "Default")
+        {
+            derives.push("Default".to_string());
+        }
+        Some(derives)
+    }
 }
@@ -4,11 +4,123 @@ use crate::dbus_common::*;
 use std::io::BufWriter;
 use std::fs::File;
 use std::io::Write;
+use convert_case::{Case, Casing};
+
+/// The standard errors in org.freedesktop.DBus.Error that the crate error
+/// enum has a variant for
+const STANDARD_ERRORS : [&str; 24] = [
+    "Failed",
+    "NoMemory",
+    "ServiceUnknown",
+    "NameHasNoOwner",
+    "NoReply",
+    "IOError",
+    "BadAddress",
+    "NotSupported",
+    "LimitsExceeded",
+    "AccessDenied",
+    "AuthFailed",
+    "NoServer",
+    "Timeout",
+    "NoNetwork",
+    "Disconnected",
+    "InvalidArgs",
+    "FileNotFound",
+    "UnknownMethod",
+    "UnknownObject",
+    "UnknownInterface",
+    "UnknownProperty",
+    "PropertyReadOnly",
+    "TimedOut",
+    "InvalidSignature",
+];
+
+/// Name of the enum of all the errors of a generated crate, e.g. SystemInterfaceError
+pub fn get_crate_error_name(project_name : &str) -> String
+{
+    project_name.to_string() + "Error"
+}
+
+/// Generates the enum of all the errors of a generated crate: the declared
+/// errors, the standard DBus errors and any other dbus::Error.
+pub fn generate_crate_error(output_writer : &mut BufWriter<File>, project_name : &str, error_names : &[&String]) -> std::io::Result<()>
+{
+    let crate_error_name = get_crate_error_name(project_name);
+
+    if let Some(name) = error_names.iter().find(|name| STANDARD_ERRORS.contains(&name.as_str()) || **name == "Other")
+    {
+        panic!("Error {} of {} has the same name as a variant of {}", name, project_name, crate_error_name);
+    }
+
+    let generated_code : rust::Tokens = quote! {
+
+        /// Any error returned by a method of this crate's interfaces
+        #[derive(Debug)]
+        pub enum $(&crate_error_name) {
+            $(for err in error_names => $(*err)($(*err)),$['\r'] )
+            $(for err in STANDARD_ERRORS => $err(String),$['\r'] )
+            Other(dbus::Error)
+        }
+
+        impl fmt::Display for $(&crate_error_name) {
+
+            fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+                match self {
+                    $(for err in error_names => $(&crate_error_name)::$(*err)(e) => write!(f, "{}", e),$['\r'] )
+                    $(for err in STANDARD_ERRORS => $(&crate_error_name)::$err(message) => write!(f, "{}", message),$['\r'] )
+                    $(&crate_error_name)::Other(e) => write!(f, "{}", e)
+                }
+            }
+        }
+
+        impl std::error::Error for $(&crate_error_name) {}
+
+        $(for err in error_names =>
+            impl From<$(*err)> for $(&crate_error_name) {
+                fn from(e : $(*err)) -> Self {
+                    $(&crate_error_name)::$(*err)(e)
+                }
+            }$['\r'])
+
+        // Errors are told apart by their DBus name
+        impl From<dbus::Error> for $(&crate_error_name) {
+            fn from(e : dbus::Error) -> Self {
+                let message = e.message().unwrap_or_default().to_string();
+                match e.name() {
+                    $(for err in error_names =>
+                        $['\r']Some($(*err)::DBUS_NAME) => $(&crate_error_name)::$(*err)(e.message().map(|m| $(*err) { message : m.to_string(), ..Default::default() }).unwrap_or_default()),)
+                    $(for err in STANDARD_ERRORS =>
+                        $['\r']Some($(quoted(format!("org.freedesktop.DBus.Error.{}", err)))) => $(&crate_error_name)::$err(message),)
+                    _ => $(&crate_error_name)::Other(e)
+                }
+            }
+        }
+
+        impl From<$(&crate_error_name)> for dbus::MethodErr {
+            fn from(e : $(&crate_error_name)) -> Self {
+                match e {
+                    $(for err in error_names => $(&crate_error_name)::$(*err)(e) => dbus::MethodErr::from(e),$['\r'] )
+                    $(for err in STANDARD_ERRORS =>
+                        $(&crate_error_name)::$err(message) => dbus::MethodErr::from(($(quoted(format!("org.freedesktop.DBus.Error.{}", err))), message)),$['\r'] )
+                    $(&crate_error_name)::Other(e) => dbus::MethodErr::from(e)
+                }
+            }
+        }
+    };
+    let generated_string = generated_code.to_file_string().unwrap();
+    output_writer.write_all(generated_string.as_bytes())?;
+    Ok(())
+}
 
 pub struct DbusError {
     name : String,
     project_name : String,
-    dbus_name : String
+    dbus_name : String,
+    /// Human readable description, used as the default message
+    description : Option<String>,
+    /// Args sent in the error reply after the message
+    args : Vec<DbusMethodArg>,
+    ext_type_usages : Vec<ExtTypeUsage>
 }
 
 impl DbusError {
@@ -21,7 +133,10 @@ impl DbusError {
             let tokens : Vec<&str> = name_str.rsplit(".").collect();
             Ok(DbusError{ name : tokens[0].to_string(),
                 project_name : tokens[2].to_string(),
-                dbus_name : name_str
+                dbus_name : name_str,
+                description : None,
+                args : Vec::new(),
+                ext_type_usages : Vec::new()
             })
         }
         else
@@ -29,21 +144,109 @@ impl DbusError {
             Err(())
         }
     }
+
+    /// Reads the errors declared in an ext:errors element, whose name
+    /// attributes are their descriptions. Each error is named by its
+    /// ext:type-name, or if it has none, after its description in Pascal case.
+    pub fn from_errors(elem : &mut Element) -> Result<Vec<DbusError>, ()>
+    {
+        let namespace = elem.attributes.get(&NAMESPACE_ATTRIBUTE).ok_or(())?.to_string();
+        let tokens : Vec<&str> = namespace.rsplit(".").collect();
+
+        let mut errors = Vec::new();
+        while let Some(mut error_elem) = elem.take_child("error")
+        {
+            let description = error_elem.attributes.get(&NAME_ATTRIBUTE).ok_or(())?.to_string();
+            let name = error_elem.attributes.get(&EXT_TYPE_NAME_ATTRIBUTE).cloned()
+                .unwrap_or_else(|| description.to_case(Case::Pascal));
+            let mut args = Vec::new();
+            let mut ext_type_usages = Vec::new();
+            let parent = format!("error {} in {}", description, namespace);
+
+            while let Some(arg_elem) = error_elem.take_child("arg")
+            {
+                ext_type_usages.extend(get_ext_type_usages(&arg_elem, &parent));
+                args.push(DbusMethodArg {
+                    name : prefix_keywords(arg_elem.attributes.get(&NAME_ATTRIBUTE).ok_or(())?),
+                    arg_type : get_dbus_type(&arg_elem, &parent)
+                });
+            }
+            errors.push(DbusError { dbus_name : namespace.clone() + "." + &name,
+                name,
+                project_name : tokens[1].to_string(),
+                description : Some(description),
+                args,
+                ext_type_usages
+            });
+        }
+        Ok(errors)
+    }
 }
 
 impl CodeGenerator for DbusError
 {
-    fn generate(&self, output_writer : &mut BufWriter<File>) -> std::io::Result<()> {
+    fn generate(&self, output_writer : &mut BufWriter<File>, type_derives : &TypeDerives) -> std::io::Result<()> {
         let name = &self.name;
+        let description = self.description.as_ref().unwrap_or(&self.name);
+        let args = &self.args;
+
+        // Errors are Clone, and args missing from a reply are left at their defaults
+        if let Some(arg) = args.iter().find(|a| ! (a.arg_type.can_derive("Clone", type_derives) && a.arg_type.can_derive("Default", type_derives)))
+        {
+            panic!("Arg {} of error {} is {}, but error args must be Clone and Default", arg.name, name, arg.arg_type.get_signature());
+        }
+
         let generated_code : rust::Tokens = quote! {
 
             #[derive(Debug, Clone)]
             pub struct $(name) {
-                pub message : String
+                pub message : String$(for arg in args => ,$['\r']pub $(&arg.name) : $(arg.arg_type.get_type_decl()))
             }
 
             impl $(name) {
                 pub const DBUS_NAME : &'static str = $(quoted (&self.dbus_name));
+                pub const DESCRIPTION : &'static str = $(quoted (description));
+
+                /// Reads the error from a reply, if it is this error. The message
+                /// and args are optional, anything missing is left at its default.
+                pub fn from_message(reply : &Message) -> Option<Self> {
+                    // Reading an error reply gives back the error's name
+                    let err = reply.read_all::<()>().err()?;
+                    if err.name() != Some(Self::DBUS_NAME) {
+                        return None;
+                    }
+
+                    let mut error = Self::default();
+                    let mut i = reply.iter_init();
+                    if let Ok(message) = i.read() {
+                        error.message = message;
+                    }
+                    $(for arg in args => $['\r']if let Ok(value) = i.read() { error.$(&arg.name) = value; })
+                    Some(error)
+                }
+
+                /// Makes the error reply to a method call, for servers
+                pub fn to_message(&self, call : &Message) -> Message {
+                    let message = std::ffi::CString::new(self.message.as_str()).unwrap_or_default();
+                    let mut reply = call.error(&Self::DBUS_NAME.into(), &message);
+                    reply.append_all(($(for arg in args => &self.$(&arg.name), )));
+                    reply
+                }
+            }
+
+            // Errors created without a message have their description
+            impl Default for $(name) {
+
+                fn default() -> Self {
+                    $(name) { message : $(name)::DESCRIPTION.to_string()$(for arg in args => , $(&arg.name) : Default::default()) }
+                }
+            }
+
+            // MethodErr has no room for args, so servers that send them should reply with to_message
+            impl From<$(name)> for dbus::MethodErr {
+                fn from(e : $(name)) -> Self {
+                    dbus::MethodErr::from(($(name)::DBUS_NAME, e.message))
+                }
             }
 
             impl fmt::Display for $(name) {
@@ -78,4 +281,8 @@ impl CodeGenerator for DbusError
     fn error_types(&self) -> Vec<std::rc::Rc<dyn CodeGenerator>> {
         Vec::new()
     }
+
+    fn ext_type_usages(&self) -> Vec<ExtTypeUsage> {
+        self.ext_type_usages.clone()
+    }
 }
@@ -1,76 +1,305 @@
 use genco::prelude::*;
 use xmltree::Element;
 use crate::dbus_common::*;
-use crate::dbus_error::DbusError;
+use crate::dbus_error::{DbusError, get_crate_error_name};
 use crate::dbus_services::*;
+use crate::dbus_property::{DbusProperty, EmitsChangedSignal};
 use crate::dbus_signal::DbusSignal;
+use crate::dbus_struct::name_anonymous_structs;
 use std::{collections::HashMap, rc::Rc};
 use std::io::BufWriter;
 use std::fs::File;
+use std::path::Path;
 use std::io::Write;
+use convert_case::{Case, Casing};
 
 
 struct DbusMethod {
-    pub return_type : DbusType,
     pub name : String,
     pub args : Vec<DbusMethodArg>,
-    pub errors : Vec<String>
-
+    /// The out args. Methods with more than one of these return a generated
+    /// <Method>Reply struct, or a tuple if any of the out args is unnamed.
+    pub outputs : Vec<DbusMethodArg>,
+    pub errors : Vec<String>,
+    /// The crate's error enum, which methods without possible errors return
+    pub crate_error_name : String
 }
 
 impl DbusMethod {
 
-    pub fn get_signature(&self) -> rust::Tokens
+    /// Name of the struct returned by methods with several named out args
+    fn get_reply_struct_name(&self) -> Option<String>
+    {
+        if self.outputs.len() > 1 && self.outputs.iter().all(|output| ! output.name.is_empty())
+        {
+            Some(self.name.to_case(Case::Pascal) + "Reply")
+        }
+        else
+        {
+            None
+        }
+    }
+
+    /// Returns the name of the generated proxy type, blocking or async
+    fn proxy_type_name(is_async : bool) -> &'static str
+    {
+        if is_async { "AsyncInterface" } else { "Interface" }
+    }
+
+    pub fn get_signature(&self, is_async : bool) -> rust::Tokens
     {
         // DBus methods return Result<Something, Error>
         let mut return_type = "-> Result<".to_string();
 
-        if ! self.return_type.type_name.is_empty()
+        match self.outputs.as_slice()
         {
-            if self.return_type.is_returned_object
-            {
-                return_type += "self::";
+            [] => {
+                // If the method return type is "void", the actual return type will
+                // be Result<(), Error>
+                return_type += "()";
+            },
+            [output] => {
+                if let DbusType::ReturnedObject(interface) = &output.arg_type
+                {
+                    // Blocking proxies borrow the connection for as long as this one does
+                    let lifetime = if is_async { "" } else { "<'a>" };
+                    return_type += &format!("self::{}::{}{}", interface, DbusMethod::proxy_type_name(is_async), lifetime);
+                }
+                else
+                {
+                    return_type += &output.arg_type.get_type_decl();
+                }
+            },
+            outputs => {
+                // Returned objects are left as paths when there are several out args
+                match self.get_reply_struct_name()
+                {
+                    Some(reply_struct_name) => return_type += &reply_struct_name,
+                    None => {
+                        let output_decls : Vec<String> = outputs.iter().map(|o| o.arg_type.get_type_decl()).collect();
+                        return_type += &format!("({})", output_decls.join(", "));
+                    }
+                }
             }
-            return_type += &self.return_type.get_type_decl();
-            if self.return_type.is_returned_object
-            {
-                return_type += "::Interface";
+        }
+        // Methods with possible errors return their own error enum
+        return_type += &format!(", {}>", self.get_error_enum_name().unwrap_or_else(|| self.crate_error_name.clone()));
+
+        quote! {pub $(if is_async => async) fn $(&self.name)(&self$(if ! &self.args.is_empty() =>, )$(for arg in &self.args join(, ) => $(arg.get_arg_declaration()))) $return_type }
+    }
+
+    /// Returns the data structure that DBus gives us when this method is called
+    pub fn get_message_type(&self) -> rust::Tokens
+    {
+        quote!(($(for output in &self.outputs => $(output.arg_type.get_type_decl()), )))
+    }
+
+    /// Generate code to convert the return from a DBus call to a value wrapped in an Ok()
+    pub fn to_okay(&self, is_async : bool) -> rust::Tokens
+    {
+        match self.outputs.as_slice()
+        {
+            [output] => {
+                if let DbusType::ReturnedObject(interface) = &output.arg_type
+                {
+                    let proxy_type_name = DbusMethod::proxy_type_name(is_async);
+                    let connection = if is_async { "self.proxy.connection.clone()" } else { "self.proxy.connection" };
+                    quote!(Ok($interface::$proxy_type_name::from_returned($connection, self.proxy.destination.clone(), return_val.0)))
+                }
+                else
+                {
+                    quote!(Ok(return_val.0))
+                }
+            },
+            outputs => {
+                match self.get_reply_struct_name()
+                {
+                    Some(reply_struct_name) => {
+                        let fields = outputs.iter().enumerate().map(|(i, output)| (&output.name, i.to_string()));
+                        quote!(Ok($reply_struct_name { $(for (name, i) in fields join(, ) => $name : return_val.$i) }))
+                    },
+                    None => quote!(Ok(return_val))
+                }
             }
         }
-        else
+    }
+
+    /// Generate the code that calls the method and maps the reply and any errors
+    pub fn get_body(&self, is_async : bool) -> rust::Tokens
+    {
+        match self.get_error_enum_name()
         {
-            // If the method return type is "void", the actual return type will
-            // be Result<(), Error>
-            return_type += "()";
+            // The reply is needed to read the args of declared errors
+            Some(error_enum_name) => quote! {
+                let mut msg = Message::method_call(&self.proxy.destination, &self.proxy.path,
+                    &INTERFACE_NAME.into(), &$(quoted (&self.name)).into());
+                msg.append_all(($(for arg in &self.args => $(&arg.name), )));
+
+                let reply = $(if is_async {
+                    method_call_reply_async(&self.proxy.connection, msg, self.proxy.timeout).await?
+                } else {
+                    method_call_reply(self.proxy.connection, msg, self.proxy.timeout)?
+                });
+
+                $(for err in &self.errors =>
+                    if let Some(err) = $(err)::from_message(&reply) {
+                        return Err($(&error_enum_name)::$(err)(err));
+                    }$['\r'])
+
+                // Anything else is a return, or an error to give back as a dbus::Error
+                let dbus_return_val : Result<$(self.get_message_type()), dbus::Error> = reply.read_all();
+
+                match dbus_return_val {
+                    Ok(return_val) => $(self.to_okay(is_async)),
+                    Err(err) => Err($(&error_enum_name)::DBus(err))
+                }
+            },
+            None => quote! {
+                // Call the method. It returns a Result<Something>
+                let dbus_return_val : Result<$(self.get_message_type()), dbus::Error>
+                    = self.proxy.method_call(INTERFACE_NAME, $(quoted (&self.name)),
+                        ($(for arg in &self.args => $(&arg.name), )))$(if is_async => .await);
+
+                // Check the return, map errors is necessary
+                match dbus_return_val {
+                    Ok(return_val) => $(self.to_okay(is_async)),
+                    Err(err) => Err($(&self.crate_error_name)::from(err))
+                }
+            }
         }
-        return_type += ", Error>";
+    }
 
-        quote! {pub fn $(&self.name)(&self$(if ! &self.args.is_empty() =>, )$(for arg in &self.args join(, ) => $(arg.get_arg_declaration()))) $return_type }
+    /// Generate the struct holding the out args of a method that has several
+    pub fn get_reply_struct(&self, type_derives : &TypeDerives) -> rust::Tokens
+    {
+        match self.get_reply_struct_name()
+        {
+            Some(reply_struct_name) => {
+                let output_types : Vec<&DbusType> = self.outputs.iter().map(|o| &o.arg_type).collect();
+                let derives = get_legal_derives(&DEFAULT_DERIVES, &output_types, type_derives);
+
+                quote! {
+                    $(get_derive_attributes(&derives))
+                    pub struct $reply_struct_name {
+                        $(for output in &self.outputs => pub $(&output.name) : $(output.arg_type.get_type_decl()),$['\r'] )
+                    }
+                }
+            },
+            None => quote!()
+        }
     }
 
-    /// Returns the data structure that DBus gives us when this method is called
-    pub fn get_message_type(&self) -> rust::Tokens
+    /// Name of the enum of errors that the method can return
+    fn get_error_enum_name(&self) -> Option<String>
     {
-        if self.return_type.is_returned_object
+        if self.errors.is_empty()
         {
-            quote!(dbus::Path)
+            None
         }
         else
         {
-            let return_type = &self.return_type;
-            quote!($(&return_type.get_type_decl()))
+            Some(self.name.to_case(Case::Pascal) + "Error")
         }
     }
 
-    /// Generate code to convert the return from a DBus call to a value wrapped in an Ok()
-    pub fn to_okay(&self) -> rust::Tokens
+    /// Generate the enum of the errors in the method's possible-errors.
+    /// Proxies return it from the method, and servers return it from their
+    /// implementation of the method.
+    pub fn get_error_enum(&self) -> rust::Tokens
     {
-        if self.return_type.is_returned_object
+        match self.get_error_enum_name()
         {
-            quote!(Ok($(&self.return_type.get_type_decl())::Interface::new(self.proxy.connection, self.proxy.destination.clone(), Some(return_val.0))))
+            Some(error_enum_name) => quote! {
+                #[derive(Debug)]
+                pub enum $(&error_enum_name) {
+                    $(for err in &self.errors => $(err)($(err)),$['\r'] )
+                    DBus(dbus::Error)
+                }
+
+                impl fmt::Display for $(&error_enum_name) {
+
+                    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+                        match self {
+                            $(for err in &self.errors => $(&error_enum_name)::$(err)(e) => write!(f, "{}", e),$['\r'] )
+                            $(&error_enum_name)::DBus(e) => write!(f, "{}", e)
+                        }
+                    }
+                }
+
+                impl std::error::Error for $(&error_enum_name) {}
+
+                $(for err in &self.errors =>
+                    impl From<$(err)> for $(&error_enum_name) {
+                        fn from(e : $(err)) -> Self {
+                            $(&error_enum_name)::$(err)(e)
+                        }
+                    }$['\r'])
+
+                // Declared errors in a dbus::Error only have their message
+                impl From<dbus::Error> for $(&error_enum_name) {
+                    fn from(e : dbus::Error) -> Self {
+                        match e.name() {
+                            $(for err in &self.errors =>
+                                $['\r']Some($(err)::DBUS_NAME) => $(&error_enum_name)::$(err)(e.message().map(|m| $(err) { message : m.to_string(), ..Default::default() }).unwrap_or_default()),)
+                            _ => $(&error_enum_name)::DBus(e)
+                        }
+                    }
+                }
+
+                impl From<$(&error_enum_name)> for dbus::MethodErr {
+                    fn from(e : $(&error_enum_name)) -> Self {
+                        match e {
+                            $(for err in &self.errors => $(&error_enum_name)::$(err)(e) => dbus::MethodErr::from(e),$['\r'] )
+                            $(&error_enum_name)::DBus(e) => dbus::MethodErr::from(e)
+                        }
+                    }
+                }
+            },
+            None => quote!()
         }
-        else {
-            quote!(Ok(return_val.0))
+    }
+
+    /// Generate the method's declaration in the Server trait. Args are passed
+    /// by value, and returned objects are returned as paths.
+    pub fn get_server_signature(&self) -> rust::Tokens
+    {
+        let return_type = match self.outputs.as_slice()
+        {
+            [] => "()".to_string(),
+            [output] => output.arg_type.get_type_decl(),
+            outputs => self.get_reply_struct_name().unwrap_or_else(|| {
+                let output_decls : Vec<String> = outputs.iter().map(|o| o.arg_type.get_type_decl()).collect();
+                format!("({})", output_decls.join(", "))
+            })
+        };
+        let error_type = self.get_error_enum_name().unwrap_or_else(|| "MethodErr".to_string());
+
+        quote!(fn $(&self.name)(&mut self$(for arg in &self.args => , $(&arg.name) : $(arg.arg_type.get_type_decl()))) -> Result<$return_type, $error_type>;)
+    }
+
+    /// Generate the code that adds the method to a dbus_crossroads interface,
+    /// calling the Server trait implementation
+    pub fn get_server_registration(&self) -> rust::Tokens
+    {
+        // Crossroads wants the in and out args as tuples
+        let to_reply = match self.outputs.as_slice()
+        {
+            [] => quote!(),
+            [_] => quote!(.map(|r| (r,))),
+            outputs => match self.get_reply_struct_name()
+            {
+                Some(_) => quote!(.map(|r| ($(for output in outputs => r.$(&output.name), )))),
+                None => quote!()
+            }
+        };
+
+        quote! {
+            b.method($(quoted (&self.name)),
+                ($(for (i, arg) in self.args.iter().enumerate() => $(quoted (arg.get_introspection_name(i))), )),
+                ($(for (i, output) in self.outputs.iter().enumerate() => $(quoted (output.get_introspection_name(i))), )),
+                |_, t : &mut T, ($(for arg in &self.args => $(&arg.name), )) : ($(for arg in &self.args => $(arg.arg_type.get_type_decl()), ))| {
+                    t.$(&self.name)($(for arg in &self.args join(, ) => $(&arg.name)))$to_reply.map_err(MethodErr::from)
+                });
         }
     }
 }
@@ -78,22 +307,39 @@ impl DbusMethod {
 pub struct DbusInterface {
     name : String,
     project_name : String,
-    service_info : DbusServiceInfo,
+    interface_name : String,
+    /// Where the object implementing the interface is by default. Interfaces
+    /// with ext:no-default-path that aren't in the services files have none.
+    service_info : Option<DbusServiceInfo>,
     methods : Vec<DbusMethod>,
     /// Error types that also need to be generated
     possible_errors : HashMap<String, Rc<dyn CodeGenerator>>,
-    signals : Vec<DbusSignal>
+    signals : Vec<DbusSignal>,
+    properties : Vec<DbusProperty>,
+    ext_type_usages : Vec<ExtTypeUsage>,
+    /// Named structs made for anonymous structs in the method args
+    anonymous_structs : Vec<Rc<dyn CodeGenerator>>,
+    ref_counting : RefCounting
 }
 
 impl DbusInterface {
 
-    pub fn new(elem : &mut Element, services : &DbusServices) -> Result<DbusInterface, ()>
+    pub fn new(elem : &mut Element, services : &DbusServices, file_name : &Path) -> Result<DbusInterface, ()>
     {
         if let Some(name) = elem.attributes.get(&NAME_ATTRIBUTE)
         {
             let name_str = name.to_string();
 
             let service_info = services.get_service_info(&name_str);
+            let no_default_path = elem.attributes.get(&EXT_NO_DEFAULT_PATH).is_some_and(|v| v == "true");
+            let ref_counting = RefCounting::from_interface(elem);
+
+            if service_info.is_none() && ! no_default_path
+            {
+                panic!("Interface {} in {} isn't in the services files ({}). Add it to one of them, \
+                    or give it ext:no-default-path=\"true\" if it is only reached as a returned object",
+                    name_str, file_name.display(), services.file_names());
+            }
 
             let tokens : Vec<&str> = name_str.rsplit(".").collect();
             let project_name = tokens[1].to_string();
@@ -101,19 +347,37 @@ impl DbusInterface {
             let mut methods : Vec<DbusMethod> = Vec::new();
             let mut possible_errors : HashMap<String, Rc<dyn CodeGenerator>> = HashMap::new();
             let mut signals : Vec<DbusSignal> = Vec::new();
+            let mut properties : Vec<DbusProperty> = Vec::new();
+            let mut ext_type_usages : Vec<ExtTypeUsage> = Vec::new();
+            let mut anonymous_structs : Vec<Rc<dyn CodeGenerator>> = Vec::new();
 
             while let Some(mut method_elem) = elem.take_child("method")
             {
-                let mut return_type = DbusType{type_name : "".to_string(), contained_types : Vec::new(), is_returned_object: false};
                 let mut args = Vec::new();
+                let mut outputs = Vec::new();
                 let mut method_errors = Vec::new();
+                let method_name = method_elem.attributes.get(&NAME_ATTRIBUTE).unwrap().to_string();
+                let parent = format!("method {} of interface {}", method_name, name_str);
 
                 while let Some(arg_elem) = method_elem.take_child("arg")
                 {
+                    ext_type_usages.extend(get_ext_type_usages(&arg_elem, &parent));
+
+                    let mut arg_type = get_dbus_type(&arg_elem, &parent);
+
+                    if *NAME_ANONYMOUS_STRUCTS
+                    {
+                        // Named after the interface too, as the structs are shared by the whole crate
+                        let arg_name = arg_elem.attributes.get(&NAME_ATTRIBUTE).cloned()
+                            .unwrap_or_else(|| format!("output{}", outputs.len()));
+                        let struct_name = tokens[0].to_string() + &method_name.to_case(Case::Pascal) + &arg_name.to_case(Case::Pascal);
+
+                        arg_type = name_anonymous_structs(arg_type, &struct_name, arg_elem.attributes.get(&EXT_FIELDS_ATTRIBUTE),
+                            &project_name, &DEFAULT_DERIVES, &mut anonymous_structs);
+                    }
+
                     if arg_elem.attributes.get(&DIRECTION_ATTRIBUTE).unwrap() == "in"
                     {
-                        let arg_type = get_dbus_type(&arg_elem);
-                        
                         args.push(DbusMethodArg {
                             name : arg_elem.attributes.get(&NAME_ATTRIBUTE).unwrap().clone(),
                             arg_type
@@ -121,7 +385,11 @@ impl DbusInterface {
                     }
                     else
                     {
-                        return_type = get_dbus_type(&arg_elem);
+                        // Out args don't need to be named
+                        outputs.push(DbusMethodArg {
+                            name : arg_elem.attributes.get(&NAME_ATTRIBUTE).map(|name| prefix_keywords(name)).unwrap_or_default(),
+                            arg_type
+                        });
                     }
                 }
 
@@ -137,25 +405,41 @@ impl DbusInterface {
 
                 methods.push(
                     DbusMethod {
-                        name : method_elem.attributes.get(&NAME_ATTRIBUTE).unwrap().to_string(),
-                        return_type,
+                        name : method_name,
                         args,
-                        errors : method_errors
+                        outputs,
+                        errors : method_errors,
+                        crate_error_name : get_crate_error_name(&project_name)
                     });
             }
 
 
             while let Some(mut signal_elem) = elem.take_child("signal")
             {
-                signals.push(DbusSignal::new(&mut signal_elem).unwrap());
+                let signal = DbusSignal::new(&mut signal_elem, &name_str).unwrap();
+                ext_type_usages.extend(signal.ext_type_usages.iter().cloned());
+                signals.push(signal);
+            }
+
+            let emits_changed_signal = EmitsChangedSignal::from_annotations(elem).unwrap_or(EmitsChangedSignal::True);
+
+            while let Some(mut property_elem) = elem.take_child("property")
+            {
+                ext_type_usages.extend(get_ext_type_usages(&property_elem, &format!("interface {}", name_str)));
+                properties.push(DbusProperty::new(&mut property_elem, emits_changed_signal, &name_str).unwrap());
             }
 
             Ok(DbusInterface{  name: tokens[0].to_string(),
                 project_name,
+                interface_name : name_str.clone(),
                 service_info,
                 methods,
                 possible_errors,
-                signals })
+                signals,
+                properties,
+                ext_type_usages,
+                anonymous_structs,
+                ref_counting })
         }
         else
         {
@@ -166,12 +450,195 @@ impl DbusInterface {
 
 impl CodeGenerator for DbusInterface {
 
-    fn generate(&self, output_writer : &mut BufWriter<File>) -> std::io::Result<()> {
+    fn generate(&self, output_writer : &mut BufWriter<File>, type_derives : &TypeDerives) -> std::io::Result<()> {
 
         let name = &self.name;
         let methods = &self.methods;
-        let service_info = &self.service_info;
+        let interface_name = &self.interface_name;
+        let has_default_path = self.service_info.as_ref().is_some_and(|s| s.object_path.is_some());
+        let path_templates = self.service_info.as_ref().map(|s| s.path_templates.as_slice()).unwrap_or_default();
+
+        // The object path and bus of the object implementing the interface
+        let default_object : rust::Tokens = match &self.service_info
+        {
+            Some(service_info) => quote! {
+                $(if let Some(object_path) = &service_info.object_path {
+                    // TODO Make these Path objects?
+                    const OBJECT_PATH : &'static str = $(quoted (object_path));
+                })
+                const DEFAULT_BUS_NAME : &'static str = $(quoted (&service_info.default_bus_name));
+
+                /// Connects to the bus that the service is on
+                pub fn default_connection() -> Result<Connection, dbus::Error> {
+                    Connection::$(service_info.bus_type.connect_function())()
+                }
+
+                /// Connects to the bus that the service is on. The IOResource must be
+                /// spawned on the tokio runtime for the connection to work.
+                #[cfg(feature = "async")]
+                pub fn default_connection_async() -> Result<(dbus_tokio::connection::IOResource<SyncConnection>, Arc<SyncConnection>), dbus::Error> {
+                    dbus_tokio::connection::$(service_info.bus_type.connect_function().to_string() + "_sync")()
+                }
+
+                /// Waits until the service is on the bus, for clients that may start before it
+                pub fn wait_for_service(connection : &Connection, timeout : Duration) -> Result<(), dbus::Error> {
+                    wait_for_name(connection, DEFAULT_BUS_NAME, timeout)
+                }
+
+                /// Calls back whenever the service's bus name changes owner, e.g. when the
+                /// service restarts. The new owner is empty while the service is gone.
+                pub fn listen_service_owner_changed<F>(connection : &Connection, callback : F) -> Result<dbus::channel::Token, dbus::Error>
+                    where F : Fn(NameOwnerChanged) -> bool + Send + 'static {
+                    listen_name_owner_changed(connection, DEFAULT_BUS_NAME, callback)
+                }
+
+                pub fn remove_service_owner_changed_listener(connection : &Connection, token : dbus::channel::Token) -> Result<(), dbus::Error> {
+                    remove_name_owner_changed_listener(connection, DEFAULT_BUS_NAME, token)
+                }
+
+                #[cfg(feature = "async")]
+                pub async fn wait_for_service_async(connection : &SyncConnection, timeout : Duration) -> Result<(), dbus::Error> {
+                    wait_for_name_async(connection, DEFAULT_BUS_NAME, timeout).await
+                }
+
+                #[cfg(feature = "async")]
+                pub async fn listen_service_owner_changed_async(connection : &SyncConnection) -> Result<NameOwnerChanges<'_>, dbus::Error> {
+                    listen_name_owner_changed_async(connection, DEFAULT_BUS_NAME).await
+                }
+            },
+            None => quote!()
+        };
 
+        // Proxies for interfaces without a default object must be given a path,
+        // so their constructors take a Path rather than an Option<Path>
+        let path_type = |lifetime : &str| -> rust::Tokens {
+            if has_default_path { quote!(Option<Path<$lifetime>>) } else { quote!(Path<$lifetime>) }
+        };
+        let path_arg = |path : rust::Tokens| -> rust::Tokens {
+            if has_default_path { quote!(Some($path)) } else { path }
+        };
+        let proxy_path : rust::Tokens = if has_default_path
+        {
+            quote!(path.unwrap_or_else(|| Path::new(OBJECT_PATH).unwrap()))
+        }
+        else
+        {
+            quote!(path)
+        };
+
+        // Proxies of returned objects hold a reference to them
+        let ref_counting = &self.ref_counting;
+        let ref_count_call = |method : &String, is_async : bool| -> rust::Tokens {
+            let call : rust::Tokens = if is_async
+            {
+                quote! {
+                    // The call is sent straight away, there's no need to await the reply
+                    let _ = self.proxy.method_call::<(), _, _, _>($(quoted(&ref_counting.interface)), $(quoted(method)), (1,));
+                }
+            }
+            else
+            {
+                quote! {
+                    let _ : Result<(), dbus::Error> =
+                        self.proxy.method_call($(quoted(&ref_counting.interface)), $(quoted(method)), (1,));
+                }
+            };
+
+            quote! {
+                if self.owns_ref
+                {
+                    $call
+                }
+            }
+        };
+
+        // Proxies made with new don't own a reference, the ones for returned objects do
+        let from_returned_doc = if ref_counting.enabled
+        {
+            " Makes a proxy for an object returned by a method. The proxy takes over\n the reference that the method gave out, and drops it when it is dropped."
+        }
+        else
+        {
+            " Makes a proxy for an object returned by a method"
+        };
+        let from_returned = |connection_type : rust::Tokens, lifetime : &str, proxy_type : &str| -> rust::Tokens {
+            quote! {
+                #[doc = $(quoted(from_returned_doc))]
+                pub fn from_returned(connection : $connection_type, bus_name : BusName<$lifetime>, path : Path<$lifetime>) -> Self {
+                    $(if ref_counting.enabled {
+                        let mut proxy = $proxy_type::new(connection, bus_name, $(path_arg(quote!(path))));
+                        proxy.owns_ref = true;
+                        proxy
+                    } else {
+                        $proxy_type::new(connection, bus_name, $(path_arg(quote!(path))))
+                    })
+                }
+            }
+        };
+
+        // Constructors for objects at paths with named segments, e.g. for_booking(connection, id)
+        let path_constructors = |connection_type : rust::Tokens, proxy_type : &str| -> rust::Tokens {
+            quote! {
+                $(for template in path_templates join ($['\r']) =>
+                    #[doc = $(quoted(format!(" Makes a proxy for the object at {}, on the service's default bus name", template.path)))]
+                    pub fn $(&template.constructor)(connection : $(&connection_type), $(for segment in &template.segments join (, ) => $(prefix_keywords(segment)) : &str)) -> Self {
+                        let path = format!($(quoted(&template.format)), $(for segment in &template.segments join (, ) => escape_path_segment($(prefix_keywords(segment)))));
+                        $proxy_type::new(connection, DEFAULT_BUS_NAME.into(), $(path_arg(quote!(Path::new(path).unwrap()))))
+                    }
+                )
+            }
+        };
+
+        let readable_properties : Vec<&DbusProperty> = self.properties.iter().filter(|p| p.readable).collect();
+        let readable_types : Vec<&DbusType> = readable_properties.iter().map(|p| &p.property_type).collect();
+        let properties_derives = get_legal_derives(&DEFAULT_DERIVES, &readable_types, type_derives);
+        // Properties that PropertiesChanged can tell us about
+        let changing_properties : Vec<&DbusProperty> = self.properties.iter()
+            .filter(|p| matches!(p.emits_changed_signal, EmitsChangedSignal::True | EmitsChangedSignal::Invalidates))
+            .collect();
+        let valued_properties : Vec<&DbusProperty> = changing_properties.iter().copied()
+            .filter(|p| p.emits_changed_signal == EmitsChangedSignal::True)
+            .collect();
+        let valued_types : Vec<&DbusType> = valued_properties.iter().map(|p| &p.property_type).collect();
+        // There's no value that ChangedProperty could default to
+        let changed_property_derives : Vec<String> = get_legal_derives(&DEFAULT_DERIVES, &valued_types, type_derives)
+            .into_iter()
+            .filter(|d| d != "Default")
+            .collect();
+        let property_name_derives : Vec<String> = ["Clone", "Copy", "PartialEq", "Eq", "Hash"].iter()
+            .map(|d| d.to_string())
+            .chain(DEFAULT_DERIVES.iter().filter(|d| ! matches!(d.as_str(), "Clone" | "PartialEq" | "Eq" | "Hash" | "Default")).cloned())
+            .collect();
+
+        let crate_error_name = get_crate_error_name(&self.project_name);
+
+        // Methods and property accessors, which only differ in the awaits
+        // between the blocking and async proxies
+        let proxy_methods = |is_async : bool| -> rust::Tokens {
+            quote! {
+                // For each DBus API method...
+                $(for method in methods => $['\r']#[allow(non_snake_case)]$['\r']$(method.get_signature(is_async))
+                {
+                    $(method.get_body(is_async))
+                })
+
+                // For each property...
+                $(for property in &self.properties => $(property.get_accessors(is_async, &crate_error_name)))
+
+                $(if ! readable_properties.is_empty() {
+                    pub $(if is_async => async) fn get_all(&self) -> Result<Properties, $(&crate_error_name)> {
+                        let props = self.proxy.get_all(INTERFACE_NAME)$(if is_async => .await)?;
+
+                        Ok(Properties {
+                            $(for property in &readable_properties =>
+                                $['\r']$(&property.field_name) : prop_value(&props, $(quoted (&property.name)))
+                                    .ok_or_else(|| $(&crate_error_name)::InvalidArgs(
+                                        $(quoted (format!("Property {} is missing or has the wrong type", property.name))).to_string()))?,)
+                        })
+                    }
+                })
+            }
+        };
  
         // See https://docs.rs/genco/0.17.2/genco/macro.quote.html
         let generated_code : rust::Tokens = quote! {
@@ -181,78 +648,117 @@ impl CodeGenerator for DbusInterface {
 
                 use super::*;
 
-                // TODO Make these Path objects?
-                const OBJECT_PATH : &'static str = $(quoted (&service_info.object_path));
-                const INTERFACE_NAME : &'static str = $(quoted (&service_info.interface_name));
+                const INTERFACE_NAME : &'static str = $(quoted (interface_name));
+
+                $default_object
 
                 // Signals associated with this interface
                 $(for signal in &self.signals => $(signal.get_tokens())$['\r'])
 
+                // Replies of methods with several out args
+                $(for method in methods => $(method.get_reply_struct(type_derives)))
+
+                // Errors that each method can return
+                $(for method in methods => $(method.get_error_enum()))
+
+                $(if ! readable_properties.is_empty() {
+                    // All the readable properties of this interface
+                    $(get_derive_attributes(&properties_derives))
+                    pub struct Properties {
+                        $(for property in &readable_properties => pub $(&property.field_name) : $(property.property_type.get_type_decl()),$['\r'] )
+                    }
+                })
+
+                $(if ! changing_properties.is_empty() {
+                    // A property whose new value was sent with PropertiesChanged
+                    $(get_derive_attributes(&changed_property_derives))
+                    pub enum ChangedProperty {
+                        $(for property in &valued_properties => $(&property.name)($(property.property_type.get_type_decl())),$['\r'] )
+                    }
+
+                    // Properties that PropertiesChanged can report as changed or invalidated
+                    $(get_derive_attributes(&property_name_derives))
+                    pub enum PropertyName {
+                        $(for property in &changing_properties => $(&property.name),$['\r'] )
+                    }
+
+                    impl PropertyName {
+                        pub fn new(name : &str) -> Option<Self> {
+                            match name {
+                                $(for property in &changing_properties => $(quoted (&property.name)) => Some(PropertyName::$(&property.name)),$['\r'] )
+                                _ => None
+                            }
+                        }
+                    }
+
+                    // Decoded PropertiesChanged signal for this interface.
+                    // Properties with unknown names or unexpected types are dropped.
+                    $(get_derive_attributes(&changed_property_derives))
+                    pub struct PropertiesChanged {
+                        pub changed : Vec<ChangedProperty>,
+                        pub invalidated : Vec<PropertyName>
+                    }
+
+                    impl PropertiesChanged {
+                        fn new(changed_properties : &HashMap<String, Variant<Box<dyn RefArg>>>, invalidated_properties : &[String]) -> Self {
+                            PropertiesChanged {
+                                changed : changed_properties.iter()
+                                    .filter_map(|(name, value)| match name.as_str() {
+                                        $(for property in &valued_properties => $(property.get_changed_value_decoder()))
+                                        _ => None
+                                    })
+                                    .collect(),
+                                invalidated : invalidated_properties.iter()
+                                    .filter_map(|name| PropertyName::new(name))
+                                    .collect()
+                            }
+                        }
+                    }
+                })
+
+                $(if ! ref_counting.enabled => #[derive(Clone)])
                 pub struct Interface<'a> {
-                    proxy : Proxy<'a, &'a Connection>
+                    proxy : Proxy<'a, &'a Connection>$(if ref_counting.enabled => ,$['\r']owns_ref : bool)
                 }
 
                 impl<'a> Interface<'a> {
 
-                    pub fn new(connection : &'a Connection, bus_name: BusName<'a>, mut path : Option<Path<'a>>) -> Self {
-
-                        if path.is_none()
-                        {
-                            path = Some(Path::new(OBJECT_PATH).unwrap());
-                        }
+                    pub fn new(connection : &'a Connection, bus_name: BusName<'a>, path : $(path_type("'a"))) -> Self {
 
                         let proxy = connection.with_proxy(bus_name,
-                            path.unwrap(),
+                            $(&proxy_path),
                             DEFAULT_TIMEOUT);
 
                         Interface {
-                            proxy
+                            proxy$(if ref_counting.enabled => ,$['\r']owns_ref : false)
                         }
                     }
 
+                    $(from_returned(quote!(&'a Connection), "'a", "Interface"))
 
-                    // For each DBus API method...
-                    $(for method in methods => $['\r']#[allow(non_snake_case)]$['\r']$(method.get_signature())
-                    {
-                        $(if ! method.return_type.type_name.is_empty()
-                        {
-                            // ...if it's not void ...
-                            //...call the method. It returns a Result<Something>
-                            $['\r']let dbus_return_val : Result<($(method.get_message_type()),), dbus::Error>
-                                = self.proxy.method_call(INTERFACE_NAME, $(quoted (&method.name)),
-                                    ($(for arg in &method.args => $(&arg.name), )));
-
-                            // Check the return, map errors is necessary
-                            match dbus_return_val {
-                                Ok(return_val) => $(&method.to_okay()),
-                                Err(err) => {
-                                    match err.name() {
-                                        $(for err in &method.errors => 
-                                            $['\r']Some(dbus_name) if dbus_name == $(err)::DBUS_NAME => Err(Box::new($(err){ message: err.message().unwrap().to_string() })),)
-                                        _ => Err(Box::new(err))
-                                    }
-                                }
-                            }
+                    $(if has_default_path {
+                        /// Makes a proxy for the object at its default path, on the service's default bus name
+                        pub fn connect_default(connection : &'a Connection) -> Self {
+                            Interface::new(connection, DEFAULT_BUS_NAME.into(), None)
                         }
-                        else
-                        {
-                            // ...if it is void ...
-                            //...call the method. It returns a Result<()>
-                            $['\r']let dbus_return_val : Result<(), dbus::Error>
-                                = self.proxy.method_call(INTERFACE_NAME, $(quoted (&method.name)),
-                                    ($(for arg in &method.args => $(&arg.name), )));
-                            // Check the return, map errors is necessary
-                            match dbus_return_val {
-                                Ok(_return_val) => Ok(()),
-                                Err(err) => {
-                                    match err.name() {
-                                        $(for err in &method.errors => 
-                                            $['\r']Some(dbus_name) if dbus_name == $(err)::DBUS_NAME => Err(Box::new($(err){ message: err.message().unwrap().to_string() })),)
-                                        _ => Err(Box::new(err))
-                                    }
+                    })
+
+                    $(path_constructors(quote!(&'a Connection), "Interface"))
+
+
+                    $(proxy_methods(false))
+
+                    $(if ! changing_properties.is_empty() {
+                        pub fn listen_properties_changed<F>(&self, callback: F)
+                            where F: Fn(PropertiesChanged) -> bool + Send + 'static {
+                                self.proxy.match_signal(move |sig: dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged, _: &Connection, _: &Message| {
+                                if sig.interface_name == INTERFACE_NAME
+                                {
+                                    callback(PropertiesChanged::new(&sig.changed_properties, &sig.invalidated_properties));
                                 }
-                            }
-                        })
+                                true
+                            }).unwrap();
+                        }
                     })
 
                     // For each signal...
@@ -267,16 +773,123 @@ impl CodeGenerator for DbusInterface {
                         })
                 }
 
-                impl<'a> Drop for Interface<'a> {
+                $(if ref_counting.enabled {
+                    // Copies of a proxy that owns a reference take one of their own. Clone
+                    // can't fail, so like drop it carries on if the call fails.
+                    impl<'a> Clone for Interface<'a> {
+
+                        fn clone(&self) -> Self {
+                            $(ref_count_call(&ref_counting.add_ref, false))
+                            Interface { proxy : self.proxy.clone(), owns_ref : self.owns_ref }
+                        }
+                    }
+
+                    impl<'a> Drop for Interface<'a> {
+
+                        fn drop(&mut self) {
+                            $(ref_count_call(&ref_counting.drop_ref, false))
+                        }
+                    }
+                })
+
+                // Implemented by services that provide this interface
+                #[cfg(feature = "server")]
+                pub trait Server {
+                    $(for method in methods => $['\r']$(method.get_server_signature()))
+                }
+
+                // Adds the interface to crossroads. Insert the returned token,
+                // with an implementation of Server, at an object path.
+                #[cfg(feature = "server")]
+                pub fn register<T : Server + Send + 'static>(cr : &mut Crossroads) -> IfaceToken<T> {
+                    cr.register(INTERFACE_NAME, |b : &mut IfaceBuilder<T>| {
+                        $(for method in methods => $(method.get_server_registration()))
+                        $(for signal in &self.signals => $(signal.get_server_registration()))
+                    })
+                }
+
+                // Non-blocking proxy, for use with dbus-tokio
+                #[cfg(feature = "async")]
+                $(if ! ref_counting.enabled => #[derive(Clone)])
+                pub struct AsyncInterface {
+                    proxy : dbus::nonblock::Proxy<'static, Arc<SyncConnection>>$(if ref_counting.enabled => ,$['\r']owns_ref : bool)
+                }
+
+                #[cfg(feature = "async")]
+                impl AsyncInterface {
+
+                    pub fn new(connection : Arc<SyncConnection>, bus_name: BusName<'static>, path : $(path_type("'static"))) -> Self {
 
-                    fn drop(&mut self) {
-                        if self.proxy.path != OBJECT_PATH.into()
-                        {
-                            let _ : Result<(), dbus::Error> =
-                                self.proxy.method_call("Zinc.DBus.RefCounted", "DropRef", (1,));
+                        let proxy = dbus::nonblock::Proxy::new(bus_name,
+                            $(&proxy_path),
+                            DEFAULT_TIMEOUT,
+                            connection);
+
+                        AsyncInterface {
+                            proxy$(if ref_counting.enabled => ,$['\r']owns_ref : false)
                         }
                     }
+
+                    $(from_returned(quote!(Arc<SyncConnection>), "'static", "AsyncInterface"))
+
+                    $(if has_default_path {
+                        /// Makes a proxy for the object at its default path, on the service's default bus name
+                        pub fn connect_default(connection : Arc<SyncConnection>) -> Self {
+                            AsyncInterface::new(connection, DEFAULT_BUS_NAME.into(), None)
+                        }
+                    })
+
+                    $(path_constructors(quote!(Arc<SyncConnection>), "AsyncInterface"))
+
+                    $(proxy_methods(true))
+
+                    // Signals are delivered as streams. The returned MsgMatch must be
+                    // kept, and passed to SyncConnection::remove_match to stop the stream.
+                    $(if ! changing_properties.is_empty() {
+                        pub async fn listen_properties_changed(&self) -> Result<(MsgMatch, impl Stream<Item = PropertiesChanged>), $(&crate_error_name)> {
+                            let rule = dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged::match_rule(
+                                Some(&self.proxy.destination), Some(&self.proxy.path)).static_clone();
+                            let (signal_match, stream) = self.proxy.connection.add_match(rule).await?.stream();
+
+                            Ok((signal_match, stream.filter_map(|(_, sig) : (Message, dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged)| {
+                                futures_util::future::ready(if sig.interface_name == INTERFACE_NAME {
+                                    Some(PropertiesChanged::new(&sig.changed_properties, &sig.invalidated_properties))
+                                } else {
+                                    None
+                                })
+                            })))
+                        }
+                    })
+
+                    // For each signal...
+                    $(for signal in &self.signals =>
+                        #[allow(non_snake_case)]$['\r']
+                        $['\r']pub async fn listen$(&signal.name)(&self) -> Result<(MsgMatch, impl Stream<Item = $(&signal.name)>), $(&crate_error_name)> {
+                            let rule = $(&signal.name)::match_rule(Some(&self.proxy.destination), Some(&self.proxy.path)).static_clone();
+                            let (signal_match, stream) = self.proxy.connection.add_match(rule).await?.stream();
+
+                            Ok((signal_match, stream.map(|(_, sig) : (Message, $(&signal.name))| sig)))
+                        })
                 }
+
+                $(if ref_counting.enabled {
+                    #[cfg(feature = "async")]
+                    impl Clone for AsyncInterface {
+
+                        fn clone(&self) -> Self {
+                            $(ref_count_call(&ref_counting.add_ref, true))
+                            AsyncInterface { proxy : self.proxy.clone(), owns_ref : self.owns_ref }
+                        }
+                    }
+
+                    #[cfg(feature = "async")]
+                    impl Drop for AsyncInterface {
+
+                        fn drop(&mut self) {
+                            $(ref_count_call(&ref_counting.drop_ref, true))
+                        }
+                    }
+                })
             }
         };
 
@@ -298,4 +911,12 @@ impl CodeGenerator for DbusInterface {
     fn error_types(&self) -> Vec<std::rc::Rc<dyn CodeGenerator>> {
         self.possible_errors.clone().into_values().collect()
     }
+
+    fn ext_type_usages(&self) -> Vec<ExtTypeUsage> {
+        self.ext_type_usages.clone()
+    }
+
+    fn anonymous_structs(&self) -> Vec<Rc<dyn CodeGenerator>> {
+        self.anonymous_structs.clone()
+    }
 }
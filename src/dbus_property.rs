@@ -0,0 +1,115 @@
+use genco::prelude::*;
+use xmltree::Element;
+use crate::dbus_common::*;
+
+/// Values of the org.freedesktop.DBus.Property.EmitsChangedSignal annotation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmitsChangedSignal {
+    /// PropertiesChanged is emitted with the new value
+    True,
+    /// PropertiesChanged is emitted without the new value
+    Invalidates,
+    /// The property never changes
+    Const,
+    /// PropertiesChanged is not emitted
+    False
+}
+
+impl EmitsChangedSignal {
+
+    /// Reads the annotation from the children of an interface or property element
+    pub fn from_annotations(elem : &mut Element) -> Option<EmitsChangedSignal>
+    {
+        let mut emits_changed_signal = None;
+
+        while let Some(annotation_elem) = elem.take_child("annotation")
+        {
+            if annotation_elem.attributes.get(&NAME_ATTRIBUTE).map(|n| n.as_str()) == Some("org.freedesktop.DBus.Property.EmitsChangedSignal")
+            {
+                emits_changed_signal = match annotation_elem.attributes.get(&VALUE_ATTRIBUTE).map(|v| v.as_str())
+                {
+                    Some("true") => Some(EmitsChangedSignal::True),
+                    Some("invalidates") => Some(EmitsChangedSignal::Invalidates),
+                    Some("const") => Some(EmitsChangedSignal::Const),
+                    Some("false") => Some(EmitsChangedSignal::False),
+                    value => panic!("Invalid EmitsChangedSignal annotation value {:?}", value)
+                };
+            }
+        }
+        emits_changed_signal
+    }
+}
+
+pub struct DbusProperty {
+    /// Name of the property on DBus
+    pub name : String,
+    /// Name of the property in the generated Properties struct
+    pub field_name : String,
+    pub property_type : DbusType,
+    pub readable : bool,
+    pub writable : bool,
+    pub emits_changed_signal : EmitsChangedSignal
+}
+
+impl DbusProperty {
+
+    /// The interface's EmitsChangedSignal annotation (if any) is the default for its properties
+    pub fn new(elem : &mut Element, default_emits_changed_signal : EmitsChangedSignal, interface_name : &str) -> Result<DbusProperty, ()>
+    {
+        if let Some(name) = elem.attributes.get(&NAME_ATTRIBUTE)
+        {
+            let name_str = name.to_string();
+            let (readable, writable) = match elem.attributes.get(&ACCESS_ATTRIBUTE).map(|a| a.as_str())
+            {
+                Some("read") => (true, false),
+                Some("write") => (false, true),
+                Some("readwrite") => (true, true),
+                _ => return Err(())
+            };
+
+            Ok(DbusProperty{
+                field_name : prefix_keywords(&name_str),
+                name : name_str,
+                property_type : get_dbus_type(elem, &format!("interface {}", interface_name)),
+                readable,
+                writable,
+                emits_changed_signal : EmitsChangedSignal::from_annotations(elem).unwrap_or(default_emits_changed_signal) })
+        }
+        else
+        {
+            Err(())
+        }
+    }
+
+    /// Generate the getter and/or setter for the property, using
+    /// org.freedesktop.DBus.Properties. They return the crate's error enum.
+    pub fn get_accessors(&self, is_async : bool, crate_error_name : &str) -> rust::Tokens
+    {
+        let type_decl = self.property_type.get_type_decl();
+
+        quote! {
+            $(if self.readable {
+                #[allow(non_snake_case)]$['\r']
+                pub $(if is_async => async) fn get$(&self.name)(&self) -> Result<$(&type_decl), $crate_error_name> {
+                    Ok(self.proxy.get(INTERFACE_NAME, $(quoted (&self.name)))$(if is_async => .await)?)
+                }
+            })
+
+            $(if self.writable {
+                #[allow(non_snake_case)]$['\r']
+                pub $(if is_async => async) fn set$(&self.name)(&self, value : &$(&type_decl)) -> Result<(), $crate_error_name> {
+                    Ok(self.proxy.set(INTERFACE_NAME, $(quoted (&self.name)), value)$(if is_async => .await)?)
+                }
+            })
+        }
+    }
+
+    /// Generate the code that picks out this property's new value from
+    /// the changed properties of a PropertiesChanged signal
+    pub fn get_changed_value_decoder(&self) -> rust::Tokens
+    {
+        quote! {
+            $(quoted (&self.name)) => variant_value(value).map(ChangedProperty::$(&self.name)),
+        }
+    }
+}
@@ -2,58 +2,201 @@ extern crate xmltree;
 
 use xmltree::Element;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use convert_case::{Case, Casing};
+use crate::dbus_common::{NAME_ATTRIBUTE, EXT_BUSNAME, EXT_BUS, EXT_CONSTRUCTOR};
+
+/// The bus that a service is on
+#[derive(Clone, Copy, PartialEq)]
+pub enum BusType {
+    System,
+    Session
+}
+
+impl BusType {
+
+    fn parse(bus : &str) -> BusType {
+        match bus
+        {
+            "system" => BusType::System,
+            "session" => BusType::Session,
+            _ => panic!("ext:bus should be system or session, not {}", bus)
+        }
+    }
+
+    /// The name of the function that connects to the bus, e.g. Connection::new_system
+    pub fn connect_function(&self) -> &'static str {
+        match self
+        {
+            BusType::System => "new_system",
+            BusType::Session => "new_session"
+        }
+    }
+}
+
+/// An object path with named segments, e.g. /Example/Booking/{id}
+#[derive(Clone)]
+pub struct PathTemplate {
+    /// The full path, with the named segments in braces
+    pub path : String,
+    /// The name of the constructor that makes a proxy for the path, e.g. for_booking
+    pub constructor : String,
+    /// The path with {} in place of each named segment
+    pub format : String,
+    /// The names of the segments, in the order they appear in the path
+    pub segments : Vec<String>
+}
+
+impl PathTemplate {
+
+    /// Returns None if the path has no named segments. The constructor is named
+    /// after the element before the last named segment, unless ext:constructor is given.
+    fn parse(object_path : &str, constructor : Option<&String>) -> Option<PathTemplate> {
+
+        if ! object_path.contains(['{', '}'])
+        {
+            return None;
+        }
+
+        let mut format = String::new();
+        let mut segments : Vec<String> = Vec::new();
+        let mut constructor_from = None;
+        let mut previous_element = None;
+
+        for element in object_path.split('/').filter(|e| ! e.is_empty())
+        {
+            format.push('/');
+
+            if let Some(segment) = element.strip_prefix('{').and_then(|e| e.strip_suffix('}'))
+            {
+                if segment.is_empty() || segment.starts_with(|c : char| c.is_ascii_digit()) ||
+                    ! segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    panic!("{} in {} isn't a valid segment name", segment, object_path);
+                }
+
+                if segments.iter().any(|s| s == segment)
+                {
+                    panic!("{} appears more than once in {}", segment, object_path);
+                }
+
+                constructor_from = previous_element.or(constructor_from);
+                previous_element = None;
+                segments.push(segment.to_string());
+                format.push_str("{}");
+            }
+            else if element.contains(['{', '}'])
+            {
+                panic!("Named segments must be a whole element of the path, not {} in {}", element, object_path);
+            }
+            else
+            {
+                previous_element = Some(element);
+                format.push_str(element);
+            }
+        }
+
+        let constructor = match constructor
+        {
+            Some(c) => c.clone(),
+            None => "for_".to_string() + &constructor_from
+                .unwrap_or_else(|| panic!("{} needs ext:constructor to name its constructor", object_path))
+                .to_case(Case::Snake)
+        };
+
+        Some(PathTemplate { path : object_path.to_string(), constructor, format, segments })
+    }
+}
 
 #[derive(Clone)]
 pub struct DbusServiceInfo {
     pub default_bus_name : String,
-    pub object_path : String,
-    pub interface_name : String
+    pub bus_type : BusType,
+    /// None if the interface is only at paths with named segments
+    pub object_path : Option<String>,
+    pub path_templates : Vec<PathTemplate>
 }
 
+#[derive(Default)]
 pub struct DbusServices {
 
-    default_bus_name : String,
-    services: HashMap<String, DbusServiceInfo>
+    services: HashMap<String, DbusServiceInfo>,
+    /// The services files read, for diagnostics
+    files : Vec<PathBuf>
 }
 
 impl DbusServices {
 
-    pub fn new(bus_name: &str) -> Self {
+    pub fn new() -> Self {
+
+        DbusServices { services : HashMap::new(), files : Vec::new() }
+    }
+
+    /// Reads a services file, which is a tree of nodes like introspection XML.
+    /// ext:busname and ext:bus apply to a node and the nodes inside it.
+    pub fn parse_services(&mut self, elem : &mut Element, file_name : &Path) {
 
-        DbusServices { default_bus_name : bus_name.into(), services : HashMap::new() }
+        self.files.push(file_name.to_path_buf());
+        self.parse_node(elem, "", "", BusType::Session);
     }
 
-    pub fn parse_services(&mut self, elem : &mut Element) {
+    fn parse_node(&mut self, elem : &mut Element, parent_path : &str, bus_name : &str, bus_type : BusType) {
 
-        while let Some(node_elem) = elem.take_child("node")
+        let bus_name = elem.attributes.get(&EXT_BUSNAME).map(|b| b.as_str()).unwrap_or(bus_name).to_string();
+        let bus_type = elem.attributes.get(&EXT_BUS).map(|b| BusType::parse(b)).unwrap_or(bus_type);
+
+        // Node names are relative to the parent node, unless they start with /
+        let object_path = match elem.attributes.get(&NAME_ATTRIBUTE)
+        {
+            Some(name) if name.starts_with('/') => name.clone(),
+            Some(name) => parent_path.trim_end_matches('/').to_string() + "/" + name,
+            None => parent_path.to_string()
+        };
+
+        let path_template = PathTemplate::parse(&object_path, elem.attributes.get(&EXT_CONSTRUCTOR));
+
+        while let Some(interface_elem) = elem.take_child("interface")
         {
-            if let Some(object_path) = node_elem.get_attribute("name")
+            if let Some(interface_name) = interface_elem.attributes.get(&NAME_ATTRIBUTE)
             {
-                if let Some(interface_element) = node_elem.get_child("interface")
+                println!("{} {}", object_path, interface_name);
+                let service_info = self.services.entry(interface_name.clone()).or_insert_with(||
+                    DbusServiceInfo{ default_bus_name: bus_name.clone(),
+                        bus_type,
+                        object_path: None,
+                        path_templates : Vec::new()});
+
+                if let Some(path_template) = &path_template
                 {
-                    if let Some(interface_name) = interface_element.get_attribute("name")
+                    if service_info.path_templates.iter().any(|t| t.constructor == path_template.constructor)
                     {
-                        println!("{} {}", object_path, interface_name);
-                        self.services.insert(interface_name.clone(),
-                            DbusServiceInfo{ default_bus_name: self.default_bus_name.clone(),
-                                object_path: "/".to_string() + object_path,
-                                interface_name : interface_name.clone()});
+                        panic!("{} has more than one path template with the constructor {}, use ext:constructor to rename one",
+                            interface_name, path_template.constructor);
                     }
+
+                    service_info.path_templates.push(path_template.clone());
+                }
+                else if service_info.object_path.is_none()
+                {
+                    // An interface implemented by several objects defaults to the first
+                    service_info.object_path = Some(object_path.clone());
                 }
             }
         }
-    }
 
-    pub fn get_service_info(&self, interface : &str) -> DbusServiceInfo {
-        match self.services.get(interface)
+        while let Some(mut node_elem) = elem.take_child("node")
         {
-            Some(service) => service.clone(),
-            None => {
-                println!("Returning default service info");
-                DbusServiceInfo { default_bus_name: self.default_bus_name.clone(),
-                    object_path: "".into(),
-                    interface_name: "".into() }
-            }
+            self.parse_node(&mut node_elem, &object_path, &bus_name, bus_type);
         }
     }
+
+    pub fn get_service_info(&self, interface : &str) -> Option<DbusServiceInfo> {
+        self.services.get(interface).cloned()
+    }
+
+    /// The services files read, separated by commas
+    pub fn file_names(&self) -> String {
+        let names : Vec<String> = self.files.iter().map(|f| f.display().to_string()).collect();
+        names.join(", ")
+    }
 }
@@ -5,21 +5,25 @@ use crate::dbus_common::*;
 
 pub struct DbusSignal {
     pub name : String,
-    pub args : Vec<DbusMethodArg>
+    pub args : Vec<DbusMethodArg>,
+    pub ext_type_usages : Vec<ExtTypeUsage>
 }
 
 impl DbusSignal {
 
-    pub fn new(elem : &mut Element) -> Result<DbusSignal, ()>
+    pub fn new(elem : &mut Element, interface_name : &str) -> Result<DbusSignal, ()>
     {
         if let Some(name) = elem.attributes.get(&NAME_ATTRIBUTE)
         {
             let name_str = name.to_string();
             let mut args = Vec::new();
+            let mut ext_type_usages = Vec::new();
+            let parent = format!("signal {} of interface {}", name_str, interface_name);
 
             while let Some(arg_elem) = elem.take_child("arg")
             {
-                let arg_type = get_dbus_type(&arg_elem);
+                let arg_type = get_dbus_type(&arg_elem, &parent);
+                ext_type_usages.extend(get_ext_type_usages(&arg_elem, &parent));
 
                 args.push(DbusMethodArg {
                     name : arg_elem.attributes.get(&NAME_ATTRIBUTE).unwrap().clone(),
@@ -29,7 +33,8 @@ impl DbusSignal {
 
             Ok(DbusSignal{
                name: name_str,
-               args})
+               args,
+               ext_type_usages})
         }
         else
         {
@@ -51,11 +56,35 @@ impl DbusSignal {
                 }
             }
 
+            impl dbus::arg::AppendAll for $(&self.name) {
+                fn append(&self, i: &mut IterAppend) {
+                    $(for arg in &self.args => self.$(&arg.name).append_by_ref(i);$['\r'])
+                }
+            }
+
             impl dbus::message::SignalArgs for $(&self.name) {
                 const NAME: &'static str = $(quoted (&self.name));
                 const INTERFACE: &'static str = INTERFACE_NAME;
             }
+
+            impl $(&self.name) {
+                // Sends the signal from the object at path
+                pub fn emit<S : dbus::channel::Sender>(&self, sender : &S, path : &Path) -> Result<(), dbus::Error> {
+                    sender.send(dbus::message::SignalArgs::to_emit_message(self, path))
+                        .map(|_| ())
+                        .map_err(|_| dbus::Error::new_failed("Failed to send the signal"))
+                }
+            }
         };
         generated_code
     }
+
+    /// Generate the code that declares the signal on a dbus_crossroads interface
+    pub fn get_server_registration(&self) -> rust::Tokens
+    {
+        quote! {
+            b.signal::<($(for arg in &self.args => $(arg.arg_type.get_type_decl()), )), _>($(quoted (&self.name)),
+                ($(for (i, arg) in self.args.iter().enumerate() => $(quoted (arg.get_introspection_name(i))), )));
+        }
+    }
 }
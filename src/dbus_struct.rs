@@ -3,6 +3,8 @@ use std::io::BufWriter;
 use std::fs::File;
 use xmltree::Element;
 use std::io::Write;
+use std::rc::Rc;
+use convert_case::{Case, Casing};
 
 use crate::dbus_common::*;
 
@@ -15,7 +17,57 @@ pub struct DbusStruct {
     members : Vec<String>,
     /// The Rust types of the members as exposed on API.
     /// These types may be user-defined in the case of enums
-    member_ext_types : Vec<DbusType>
+    member_ext_types : Vec<DbusType>,
+    ext_type_usages : Vec<ExtTypeUsage>,
+    /// Derives asked for, which are only used if all the members allow them
+    requested_derives : Vec<String>,
+    /// Named structs made for anonymous structs in the members
+    anonymous_structs : Vec<Rc<dyn CodeGenerator>>
+}
+
+/// Replaces the anonymous structs in dbus_type with user defined types,
+/// adding a DbusStruct for each of them to structs. The outermost struct
+/// is called name, and the structs nested in it are named after it and
+/// their field. Fields are named by field_names (a comma separated list)
+/// if given, otherwise field0, field1 and so on.
+pub fn name_anonymous_structs(dbus_type : DbusType, name : &str, field_names : Option<&String>,
+    project_name : &str, requested_derives : &[String], structs : &mut Vec<Rc<dyn CodeGenerator>>) -> DbusType
+{
+    match dbus_type
+    {
+        DbusType::Struct(member_types) => {
+            let members : Vec<String> = match field_names
+            {
+                Some(field_names) => field_names.split(',').map(|f| prefix_keywords(f.trim())).collect(),
+                None => (0..member_types.len()).map(|n| format!("field{}", n)).collect()
+            };
+
+            if members.len() != member_types.len()
+            {
+                panic!("ext:fields {} of {} should name {} fields", field_names.unwrap(), name, member_types.len());
+            }
+
+            let member_ext_types = member_types.into_iter().zip(&members)
+                .map(|(t, m)| name_anonymous_structs(t, &(name.to_string() + &m.to_case(Case::Pascal)),
+                    None, project_name, requested_derives, structs))
+                .collect();
+
+            structs.push(Rc::new(DbusStruct { name : name.to_string(),
+                project_name : project_name.to_string(),
+                members,
+                member_ext_types,
+                ext_type_usages : Vec::new(),
+                requested_derives : requested_derives.to_vec(),
+                anonymous_structs : Vec::new() }));
+
+            DbusType::Ext(name.to_string())
+        },
+        DbusType::Array(element) =>
+            DbusType::Array(Box::new(name_anonymous_structs(*element, name, field_names, project_name, requested_derives, structs))),
+        DbusType::Dict(key, value) =>
+            DbusType::Dict(key, Box::new(name_anonymous_structs(*value, name, field_names, project_name, requested_derives, structs))),
+        dbus_type => dbus_type
+    }
 }
 
 impl DbusStruct {
@@ -28,17 +80,34 @@ impl DbusStruct {
 
             let mut members : Vec<String> = Vec::new();
             let mut member_ext_types : Vec<DbusType> = Vec::new();
+            let mut ext_type_usages : Vec<ExtTypeUsage> = Vec::new();
+            let requested_derives = get_requested_derives(elem);
+            let mut anonymous_structs : Vec<Rc<dyn CodeGenerator>> = Vec::new();
+            let parent = format!("struct {}", name_str);
 
             while let Some(child) = elem.take_child("member")
             {
-                members.push(prefix_keywords(child.attributes.get(&NAME_ATTRIBUTE).unwrap()));
-                member_ext_types.push(get_dbus_type(&child));
+                let member_name = child.attributes.get(&NAME_ATTRIBUTE).unwrap();
+                let mut member_type = get_dbus_type(&child, &parent);
+
+                if *NAME_ANONYMOUS_STRUCTS
+                {
+                    member_type = name_anonymous_structs(member_type, &(tokens[0].to_string() + &member_name.to_case(Case::Pascal)),
+                        child.attributes.get(&EXT_FIELDS_ATTRIBUTE), tokens[1], &requested_derives, &mut anonymous_structs);
+                }
+
+                members.push(prefix_keywords(member_name));
+                member_ext_types.push(member_type);
+                ext_type_usages.extend(get_ext_type_usages(&child, &parent));
             }
 
             Ok( DbusStruct { name: tokens[0].to_string(),
                 project_name : tokens[1].to_string(),
                 members,
-                member_ext_types} )
+                member_ext_types,
+                ext_type_usages,
+                requested_derives,
+                anonymous_structs} )
         }
         else
         {
@@ -49,7 +118,7 @@ impl DbusStruct {
 
 impl CodeGenerator for DbusStruct {
 
-    fn generate(&self, output_writer : &mut BufWriter<File>) -> std::io::Result<()>{
+    fn generate(&self, output_writer : &mut BufWriter<File>, type_derives : &TypeDerives) -> std::io::Result<()>{
 
         let name = &self.name;
         let members = &self.members;
@@ -62,13 +131,15 @@ impl CodeGenerator for DbusStruct {
             member_initialisers.push("members.".to_string() + &n.to_string());
         }
 
+        let derives = self.derives(type_derives).unwrap();
+
         // See https://docs.rs/genco/0.17.2/genco/macro.quote.html
         let generated_code : rust::Tokens = quote! {
             #[allow(non_camel_case_types)]$['\r']
             pub type $(name)Message = ($(for t in member_ext_types => $(t.get_type_decl()), ));
 
             #[allow(non_camel_case_types)]$['\r']
-            #[derive(Debug, Clone)]
+            $(get_derive_attributes(&derives))
             pub struct $name {
                 $(for (m, t) in members.into_iter().zip(member_ext_types) join(, ) => $['\r']pub $m : $(t.get_type_decl()))
             }
@@ -83,11 +154,10 @@ impl CodeGenerator for DbusStruct {
                 }
             }
 
+            // A struct is a single (..) argument, the same as its Message tuple
             impl dbus::arg::ReadAll for $name {
                 fn read(i: &mut dbus::arg::Iter) -> Result<Self, dbus::arg::TypeMismatchError> {
-                    Ok($name {
-                        $(for m in members => $['\r']$m : i.read().unwrap(),)
-                    })
+                    i.read()
                 }
             }
 
@@ -110,11 +180,9 @@ impl CodeGenerator for DbusStruct {
 
             impl dbus::arg::Append for $(name) {
                 fn append_by_ref(&self, i: &mut IterAppend<'_>) {
-                    $(for m in members => $['\r']self.$m.append_by_ref(i);)
-                }
-
-                fn append(self, i: &mut IterAppend<'_>) {
-                    $(for m in members => $['\r']self.$m.append_by_ref(i);)
+                    i.append_struct(|s| {
+                        $(for m in members => $['\r']self.$m.append_by_ref(s);)
+                    });
                 }
             }
         };
@@ -139,4 +207,17 @@ impl CodeGenerator for DbusStruct {
     fn error_types(&self) -> Vec<std::rc::Rc<dyn CodeGenerator>> {
         Vec::new()
     }
+
+    fn ext_type_usages(&self) -> Vec<ExtTypeUsage> {
+        self.ext_type_usages.clone()
+    }
+
+    fn derives(&self, type_derives : &TypeDerives) -> Option<Vec<String>> {
+        let member_types : Vec<&DbusType> = self.member_ext_types.iter().collect();
+        Some(get_legal_derives(&self.requested_derives, &member_types, type_derives))
+    }
+
+    fn anonymous_structs(&self) -> Vec<Rc<dyn CodeGenerator>> {
+        self.anonymous_structs.clone()
+    }
 }
@@ -8,32 +8,39 @@ mod dbus_common;
 mod dbus_enum;
 mod dbus_error;
 mod dbus_interface;
+mod dbus_property;
 mod dbus_signal;
 mod dbus_services;
 mod dbus_struct;
 
 use crate_files::CrateFiles;
-use dbus_common::{CodeGenerator, make_lib_output_writer, Args, EXT_BUSNAME};
+use dbus_common::{CodeGenerator, DbusType, TypeDerives, make_lib_output_writer, Args, EXT_BUSNAME};
 use dbus_enum::DbusEnum;
+use dbus_error::{DbusError, generate_crate_error};
 use dbus_interface::DbusInterface;
 use dbus_services::DbusServices;
 use dbus_struct::DbusStruct;
 
 use clap::Parser;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use xmltree::Element;
 use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use convert_case::{Case, Casing};
 
-fn make_generator(root_element: &mut Element, services : &DbusServices) -> Option<Rc<dyn CodeGenerator>>
+fn make_generator(root_element: &mut Element, services : &DbusServices, file_name : &Path) -> Option<Rc<dyn CodeGenerator>>
 {
     if let Some(enum_element) = root_element.get_mut_child("enum")
     {
         // Enums
         return Some(Rc::new(DbusEnum::new(enum_element).unwrap()));
     }
+    else if let Some(flags_element) = root_element.get_mut_child("flags")
+    {
+        // Flags are enums whose values can be combined
+        return Some(Rc::new(DbusEnum::new(flags_element).unwrap()));
+    }
     else if let Some(struct_element) = root_element.get_mut_child("struct")
     {
         // Structs
@@ -41,7 +48,7 @@ fn make_generator(root_element: &mut Element, services : &DbusServices) -> Optio
     }
     else if let Some(interface_element) = root_element.get_mut_child("interface")
     {
-        return Some(Rc::new(DbusInterface::new(interface_element, services).unwrap()));
+        return Some(Rc::new(DbusInterface::new(interface_element, services, file_name).unwrap()));
     }
     else
     {
@@ -79,20 +86,13 @@ fn main() {
 
         // Annoyingly, we first have to parse all the input XMLs to find the ones
         // containing the service infomation (which can have any name)
-        if let Some(bus_name) = root_element.attributes.get_mut(&EXT_BUSNAME).cloned()
+        if root_element.attributes.contains_key(&EXT_BUSNAME)
         {
-            if services.is_none()
-            {
-                services = Some(DbusServices::new(bus_name.as_str()));
-            }
-
-            let mut s = services.unwrap();
-            s.parse_services(&mut root_element);
-            services = Some(s);
+            services.get_or_insert_with(DbusServices::new).parse_services(&mut root_element, &file_name);
         }
         else
         {
-            input_xmls.push(root_element);
+            input_xmls.push((file_name, root_element));
         }
     }
 
@@ -101,33 +101,108 @@ fn main() {
         panic!("No service info found in input XMLs");
     }
 
-    for mut root_element in input_xmls
+    let mut generators = Vec::new();
+
+    for (file_name, mut root_element) in input_xmls
     {
-        match make_generator(&mut root_element, services.as_ref().unwrap())
+        if let Some(errors_element) = root_element.get_mut_child("errors")
+        {
+            // Errors are generated once each, whether or not any method refers to them
+            for error in DbusError::from_errors(errors_element).unwrap()
+            {
+                error_types.insert(error.name().clone(), Rc::new(error));
+            }
+            continue;
+        }
+
+        match make_generator(&mut root_element, services.as_ref().unwrap(), &file_name)
         {
-            Some(g) => {
-                let project_name = g.project_name().clone();
-                client_libs.insert(project_name);
-                let mut output_src_dir = make_output_dir_name(&args.output_dir, g.project_name());
-
-                output_src_dir.push("src");
-                let output_writer
-                    = output_writers
-                    .entry(output_src_dir.clone())
-                    .or_insert_with(|| make_lib_output_writer(&output_src_dir, "lib.rs").unwrap());
-
-                g.generate(output_writer).unwrap();
-                for error_generator in g.error_types()
+            Some(g) => generators.push(g),
+            None => println!("Unhandled element type")
+        }
+    }
+
+    // Structs named for anonymous structs are generated like any other struct
+    let anonymous_structs : Vec<Rc<dyn CodeGenerator>> = generators.iter()
+        .flat_map(|g| g.anonymous_structs())
+        .collect();
+    generators.extend(anonymous_structs);
+
+    // Check that user defined types are used with the DBus type they are sent as
+    let wire_types : HashMap<&String, DbusType> = generators.iter()
+        .filter_map(|g| g.wire_type().map(|wire_type| (g.name(), wire_type)))
+        .collect();
+
+    for g in generators.iter().chain(error_types.values())
+    {
+        for usage in g.ext_type_usages()
+        {
+            if let Some(wire_type) = wire_types.get(&usage.name)
+            {
+                if *wire_type != usage.wire_type
                 {
-                    error_types.insert(error_generator.name().clone(), error_generator);
+                    panic!("{} should be {} to match the DBus type {} is sent as",
+                        usage.context, wire_type.get_signature(), usage.name);
                 }
-            },
-            None => println!("Unhandled element type")
+            }
+        }
+    }
+
+    // Every error that a method can return must be declared in an errors file
+    for g in &generators
+    {
+        for error in g.error_types()
+        {
+            if ! error_types.get(error.name()).is_some_and(|declared| declared.project_name() == error.project_name())
+            {
+                panic!("Error {} in the possible errors of {} isn't declared in any ext:errors file",
+                    error.name(), g.name());
+            }
+        }
+    }
+
+    // Work out what each user defined type can derive. A type can only derive
+    // a trait if the types it contains do, so go round until nothing changes.
+    let mut type_derives = TypeDerives::new();
+    loop
+    {
+        let mut changed = false;
+        for g in &generators
+        {
+            if let Some(derives) = g.derives(&type_derives)
+            {
+                if type_derives.get(g.name()) != Some(&derives)
+                {
+                    type_derives.insert(g.name().clone(), derives);
+                    changed = true;
+                }
+            }
+        }
+
+        if ! changed
+        {
+            break;
         }
     }
 
+    for g in generators
+    {
+        let project_name = g.project_name().clone();
+        client_libs.insert(project_name);
+        let mut output_src_dir = make_output_dir_name(&args.output_dir, g.project_name());
+
+        output_src_dir.push("src");
+        let output_writer
+            = output_writers
+            .entry(output_src_dir.clone())
+            .or_insert_with(|| make_lib_output_writer(&output_src_dir, "lib.rs").unwrap());
+
+        g.generate(output_writer, &type_derives).unwrap();
+    }
+
     for error in error_types.values()
     {
+        client_libs.insert(error.project_name().clone());
         let mut output_src_dir = make_output_dir_name(&args.output_dir,
             error.project_name());
         output_src_dir.push("src");
@@ -137,7 +212,27 @@ fn main() {
             .entry(output_src_dir.clone())
             .or_insert_with(|| make_lib_output_writer(&output_src_dir, "lib.rs").unwrap());
 
-        error.generate(output_writer).unwrap();
+        error.generate(output_writer, &type_derives).unwrap();
+    }
+
+    // Each crate has an enum of all its errors
+    for name in &client_libs
+    {
+        let mut error_names : Vec<&String> = error_types.values()
+            .filter(|error| error.project_name() == name)
+            .map(|error| error.name())
+            .collect();
+        error_names.sort();
+
+        let mut output_src_dir = make_output_dir_name(&args.output_dir, name);
+        output_src_dir.push("src");
+
+        let output_writer
+            = output_writers
+            .entry(output_src_dir.clone())
+            .or_insert_with(|| make_lib_output_writer(&output_src_dir, "lib.rs").unwrap());
+
+        generate_crate_error(output_writer, name, &error_names).unwrap();
     }
 
     // Generate the Cargo package file
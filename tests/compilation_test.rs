@@ -4,11 +4,13 @@ use glob::glob;
 use fs_extra::dir::{CopyOptions, copy};
 
 struct Cleaner
-{}
+{
+    dirs : Vec<&'static str>
+}
 
 impl Cleaner {
-    pub fn new() -> Self {
-        Cleaner{}
+    pub fn new(dirs : &[&'static str]) -> Self {
+        Cleaner{ dirs : dirs.to_vec() }
     }
 }
 
@@ -16,7 +18,8 @@ impl Drop for Cleaner
 {
     fn drop(&mut self) {
         let cleanup_output = Command::new("rm")
-            .args(["-r", concat!(env!("CARGO_TARGET_TMPDIR"), "/test_app"), concat!(env!("CARGO_TARGET_TMPDIR"), "/system_interface")])
+            .arg("-r")
+            .args(&self.dirs)
             .output()
             .expect("Failed to clean up");
 
@@ -27,14 +30,35 @@ impl Drop for Cleaner
     }
 }
 
+/// Runs cargo in a generated crate or the test app
+fn cargo(dir : &str, args : &[&str])
+{
+    let cargo_output = Command::new("cargo")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("Failed to run cargo");
+
+    io::stdout().write_all(&cargo_output.stdout).unwrap();
+    io::stderr().write_all(&cargo_output.stderr).unwrap();
+
+    assert!(cargo_output.status.success(), "cargo {:?} failed in {}", args, dir);
+}
+
+/// Runs cargo build with extra args such as features
+fn build_with(dir : &str, args : &[&str])
+{
+    cargo(dir, &[&["build"], args].concat());
+}
+
 #[test]
 fn generate_and_compile()
 {
-    let _cleaner = Cleaner::new();
+    let _cleaner = Cleaner::new(&[concat!(env!("CARGO_TARGET_TMPDIR"), "/test_app"), concat!(env!("CARGO_TARGET_TMPDIR"), "/system_interface")]);
 
     let mut compile_command = Command::new(env!("CARGO_BIN_EXE_dbus-binding-rs"));
 
-    compile_command.args(["--output-dir", env!("CARGO_TARGET_TMPDIR")]);
+    compile_command.args(["--output-dir", env!("CARGO_TARGET_TMPDIR"), "--derive", "Clone,PartialEq,serde", "--name-anonymous-structs"]);
 
     for file in glob("xml/*/*.xml").expect("Failed to read directory")
     {
@@ -49,29 +73,52 @@ fn generate_and_compile()
 
     assert!(gen_output.status.success());
 
-    let compile_output = Command::new("cargo")
-        .arg("build")
-        .current_dir(concat!(env!("CARGO_TARGET_TMPDIR"), "/system_interface"))
-        .output()
-        .expect("Failed to compile lib");
-
-    io::stdout().write_all(&compile_output.stdout).unwrap();
-    io::stderr().write_all(&compile_output.stderr).unwrap();
-
-    assert!(compile_output.status.success());
+    let lib_dir = concat!(env!("CARGO_TARGET_TMPDIR"), "/system_interface");
+    build_with(lib_dir, &[]);
+    build_with(lib_dir, &["--features", "async"]);
+    build_with(lib_dir, &["--features", "server"]);
+    build_with(lib_dir, &["--features", "serde"]);
 
     let mut copy_options = CopyOptions::new();
     copy_options.overwrite = true;
     copy("tests/test_app", env!("CARGO_TARGET_TMPDIR"), &copy_options).unwrap();
 
-    let compile_app_output = Command::new("cargo")
-        .arg("build")
-        .current_dir(concat!(env!("CARGO_TARGET_TMPDIR"), "/test_app"))
-        .output()
-        .expect("Failed to compile test application");
+    let app_dir = concat!(env!("CARGO_TARGET_TMPDIR"), "/test_app");
+    build_with(app_dir, &[]);
+    cargo(app_dir, &["run", "--", "checks"]);
+
+    // These serve the objects they call, so only need a session bus
+    if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some()
+    {
+        cargo(app_dir, &["run", "--", "refcount"]);
+        cargo(app_dir, &["run", "--", "errors"]);
+    }
+}
 
-    io::stdout().write_all(&compile_app_output.stdout).unwrap();
-    io::stderr().write_all(&compile_app_output.stderr).unwrap();
+// Anonymous structs as tuples, and only the default derives
+#[test]
+fn generate_and_compile_defaults()
+{
+    let _cleaner = Cleaner::new(&[concat!(env!("CARGO_TARGET_TMPDIR"), "/defaults")]);
+
+    let mut compile_command = Command::new(env!("CARGO_BIN_EXE_dbus-binding-rs"));
+
+    compile_command.args(["--output-dir", concat!(env!("CARGO_TARGET_TMPDIR"), "/defaults")]);
+
+    for file in glob("xml/*/*.xml").expect("Failed to read directory")
+    {
+        compile_command.arg(file.unwrap().into_os_string());
+    }
+
+    let gen_output = compile_command.output()
+        .expect("Failed to generate code");
+
+    io::stdout().write_all(&gen_output.stdout).unwrap();
+    io::stderr().write_all(&gen_output.stderr).unwrap();
+
+    assert!(gen_output.status.success());
 
-    assert!(compile_app_output.status.success());
+    let lib_dir = concat!(env!("CARGO_TARGET_TMPDIR"), "/defaults/system_interface");
+    build_with(lib_dir, &[]);
+    build_with(lib_dir, &["--all-features"]);
 }
@@ -1,14 +1,31 @@
 extern crate system_interface;
 extern crate dbus;
 
-use system_interface::Error8;
+use system_interface::{Enum2, Enum7, Enum8, Enum10, Flags0, Flags1, SystemServiceMethod16Arg, Struct14, Struct19, Struct22};
+use system_interface::{Error8, BookingExpired, SystemInterfaceError};
+use system_interface::{variant, variant_value, prop_value, owned_fd};
+use system_interface::SystemService;
+use system_interface::SystemService::Signal5;
+use system_interface::SystemService::{ChangedProperty, PropertiesChanged};
+use system_interface::SystemService::Interface as LA;
 use system_interface::SystemService2::Signal0;
 use system_interface::SystemService2::Signal1;
 use system_interface::SystemService2::Signal2;
 use system_interface::SystemService2::Signal3;
 use system_interface::SystemService2::Interface as LA2;
+use system_interface::SystemService2::Method4Error;
+use system_interface::Booking::Interface as BookingInterface;
 use dbus::blocking::Connection;
+use dbus::Message;
+use dbus::message::MatchRule;
+use dbus::channel::{MatchingReceiver, Sender};
 use std::time::Duration;
+use std::collections::HashMap;
+use std::fs::File;
+use dbus::{Path, Signature};
+use system_interface::dbus_crossroads::{Crossroads, IfaceBuilder};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI32, Ordering};
 
 fn call_method_5(la : &LA2)
 {
@@ -21,20 +38,270 @@ fn call_method_4(la : &LA2, method_arg : &String)
 {
     match la.method4(method_arg) {
         Ok(val) => { println!("Value {:?}", val) },
-        Err(err) => {
-            if let Some(exception) = err.downcast_ref::<Error8>()
-            {
-                println!("{}", exception);
-            }
-            else
-            {
-                println!("Unexpected error: {}", err);
+        Err(Method4Error::Error8(exception)) => {
+            println!("{}: {} (code {}, fields {:?})", Error8::DESCRIPTION, exception, exception.code, exception.fields)
+        },
+        Err(Method4Error::DBus(err)) => { println!("Unexpected error: {}", err) }
+    }
+}
+
+fn call_method_16(la : &LA, name : &String)
+{
+    let mut points = HashMap::new();
+    points.insert(name.clone(), vec![SystemServiceMethod16Arg { x : 1, y : 2 }]);
+
+    match la.method16(&points) {
+        Ok(results) => { for result in results { println!("{} {:?}", result.field0, result.field1) } },
+        Err(err) => { println!("Unexpected error: {}", err) }
+    }
+}
+
+fn call_method_18(la : &LA, method_arg : &String)
+{
+    let mut options = HashMap::new();
+    options.insert("verbose".to_string(), variant(true));
+    options.insert("limit".to_string(), variant(10u32));
+
+    match la.method18(method_arg, &options) {
+        Ok(val) => { println!("Value {:?}", variant_value::<Vec<String>>(&val)) },
+        Err(SystemInterfaceError::ServiceUnknown(message)) => { println!("Service not running: {}", message) },
+        Err(err) => { println!("Unexpected error: {}", err) }
+    }
+}
+
+fn call_method_19(la : &LA, file_name : &String)
+{
+    let file = File::open(file_name).unwrap();
+
+    match la.method19(&Path::from("/Example/SystemInterface/Upload"), &owned_fd(file), &Signature::from("ay")) {
+        Ok(paths) => { for path in paths { println!("Path {}", path) } },
+        Err(err) => { println!("Unexpected error: {}", err) }
+    }
+}
+
+fn call_method_21(la : &LA, method_arg : &String)
+{
+    match la.method21(method_arg) {
+        Ok(reply) => {
+            match reply.status {
+                Enum7::SUCCESS => println!("Payload {:?}", reply.payload),
+                Enum7::Unknown(status) => println!("Unknown status {}", status)
             }
+        },
+        Err(err) => { println!("Unexpected error: {}", err) }
+    }
+
+    let (count, name, _options) = la.method22().unwrap();
+    println!("{} {}", count, name);
+
+    let counts = la.method23(&Enum8::from(method_arg.as_str())).unwrap();
+    for (state, count) in counts
+    {
+        match state {
+            Enum8::Other(state) => println!("Unknown state {} {}", state, count),
+            state => println!("{} {}", state.value(), count)
         }
     }
 }
 
+fn cancel_booking(booking : &BookingInterface, reason : &String)
+{
+    match booking.getReference() {
+        Ok(reference) => println!("Cancelling {}", reference),
+        Err(err) => { println!("Unexpected error: {}", err) }
+    }
+
+    if let Err(err) = booking.cancel(reason) {
+        println!("Unexpected error: {}", err);
+    }
+}
+
+// Serves a booking that counts its references, and checks that the
+// proxies made for it give back every reference they take
+fn check_ref_counts()
+{
+    let refs = Arc::new(AtomicI32::new(0));
+    let server = Connection::new_session().unwrap();
+    let server_name = server.unique_name().to_string();
+    let mut cr = Crossroads::new();
+
+    let refs_given = refs.clone();
+    let service = cr.register("Example.SystemInterface.SystemService", move |b : &mut IfaceBuilder<()>| {
+        b.method("method25", ("arg",), ("result",), move |_, _, (id,) : (String,)| {
+            // The caller gets a reference to the booking with it
+            refs_given.fetch_add(1, Ordering::SeqCst);
+            Ok((Path::from(format!("/Example/SystemInterface/Booking/{}", id)),))
+        });
+    });
+
+    let refs_taken = refs.clone();
+    let refs_dropped = refs.clone();
+    let ref_counted = cr.register("Zinc.DBus.RefCounted", move |b : &mut IfaceBuilder<()>| {
+        b.method("Retain", ("count",), (), move |_, _, (count,) : (i32,)| {
+            refs_taken.fetch_add(count, Ordering::SeqCst);
+            Ok(())
+        });
+        b.method("Release", ("count",), (), move |_, _, (count,) : (i32,)| {
+            refs_dropped.fetch_sub(count, Ordering::SeqCst);
+            Ok(())
+        });
+    });
+
+    cr.insert("/", &[service], ());
+    cr.insert("/Example/SystemInterface/Booking/1", &[ref_counted], ());
+    std::thread::spawn(move || cr.serve(&server));
+
+    let connection = Connection::new_session().unwrap();
+    let la = LA::new(&connection, server_name.into(), Some(Path::from("/")));
+
+    // A returned booking and its copy each own a reference
+    let booking = la.method25(&"1".to_string()).unwrap();
+    let booking_copy = booking.clone();
+    drop(booking);
+    assert_eq!(refs.load(Ordering::SeqCst), 1);
+    drop(booking_copy);
+
+    // Proxies made from a path don't own one
+    let booking = BookingInterface::for_booking(&connection, "1");
+    drop(booking.clone());
+    drop(booking);
+
+    assert_eq!(refs.load(Ordering::SeqCst), 0, "Booking references don't balance");
+    println!("Booking references balance");
+}
+
+// Serves method4 with an error reply that has args, and checks that the
+// proxy decodes them
+fn check_error_args()
+{
+    let server = Connection::new_session().unwrap();
+    let server_name = server.unique_name().to_string();
+
+    server.start_receive(MatchRule::new_method_call(), Box::new(|call, server| {
+        let error = Error8 { message : "No such booking".to_string(), code : 42, fields : vec!["arg".to_string()] };
+        server.send(error.to_message(&call)).unwrap();
+        true
+    }));
+    std::thread::spawn(move || loop { server.process(Duration::from_millis(1000)).unwrap(); });
+
+    let connection = Connection::new_session().unwrap();
+    let la = LA2::new(&connection, server_name.into(), None);
+
+    match la.method4(&"1".to_string()) {
+        Err(Method4Error::Error8(error)) => {
+            assert_eq!(error.message, "No such booking");
+            assert_eq!(error.code, 42);
+            assert_eq!(error.fields, vec!["arg".to_string()]);
+        },
+        other => panic!("Expected Error8 from method4, got {:?}", other)
+    }
+    println!("Error args decoded");
+}
+
+fn make_struct_22() -> Struct22
+{
+    let point = |x, y| Struct14 { member0 : x, member1 : y };
+
+    Struct22 {
+        origin : point(0, 0),
+        named : HashMap::from([("home".to_string(), point(1, 2)), ("work".to_string(), point(3, 4))]),
+        route : vec![point(0, 0), point(5, 6)]
+    }
+}
+
+// Structs nested in a struct, as dictionary values and in arrays are marshalled as (..)
+fn round_trip_struct_22()
+{
+    let sent = make_struct_22();
+    let msg = Message::new_method_call("Example.SystemService", "/", "Example.SystemService", "method24").unwrap()
+        .append1(&sent);
+    let received : Struct22 = msg.read1().unwrap();
+
+    assert!(received == sent);
+}
+
+// Messages that don't hold the type give a TypeMismatchError rather than a panic
+fn read_mismatched_types()
+{
+    let call = || Message::new_method_call("Example.SystemService", "/", "Example.SystemService", "method24").unwrap();
+
+    assert!(call().append1(5).read1::<Struct22>().is_err());
+    // A struct with the right shape but with the wrong type inside
+    let wrong_point = (("0".to_string(), "0".to_string()), HashMap::<String, (i64, i64)>::new(), Vec::<(i64, i64)>::new());
+    assert!(call().append1(wrong_point).read1::<Struct22>().is_err());
+    assert!(call().append1("success").read1::<Enum7>().is_err());
+}
+
+fn compare_enums()
+{
+    // Enums compare by value, even when the catch-all variant holds a known value
+    assert!(Enum7::Unknown(Enum7::SUCCESS.value()) == Enum7::SUCCESS);
+    assert!(Enum8::Other("active".to_string()) == Enum8::ACTIVE);
+    // and order by value to agree
+    assert!(Enum8::Other("active".to_string()).partial_cmp(&Enum8::ACTIVE) == Some(std::cmp::Ordering::Equal));
+    assert!(Enum10::Unknown(2) > Enum10::FIRST && Enum10::Unknown(2) < Enum10::LAST);
+    assert!(Enum10::Unknown(Enum10::LAST.value()).cmp(&Enum10::LAST) == std::cmp::Ordering::Equal);
+
+    // NONE has no bits, so it isn't one of the flags that are set
+    assert!(Flags1::PINNED.iter().eq([Flags1::PINNED]));
+}
+
+// Clone and PartialEq are derived for every type from the command line
+fn compare_derived()
+{
+    let booking = Struct19::new(("a".to_string(), "b".to_string(), "c".to_string(), "d".to_string(), Enum2::new(0), 1, "e".to_string()));
+    assert!(booking.clone() == booking);
+}
+
+// Checks that don't need a bus, run by the compilation test
+fn run_checks()
+{
+    round_trip_struct_22();
+    read_mismatched_types();
+    compare_enums();
+    compare_derived();
+    println!("Checks passed");
+}
+
+fn call_method_24(la : &LA)
+{
+    match la.method24(&make_struct_22()) {
+        Ok(places) => { for (name, place) in places { println!("{} {:?}", name, place.member0) } },
+        Err(err) => { println!("Unexpected error: {}", err) }
+    }
+}
+
+fn show_properties(la : &LA)
+{
+    match la.get_all() {
+        Ok(props) => {
+            println!("Property0 {} Property1 {} Property3 {:?}", props.Property0, props.Property1, props.Property3);
+            println!("Property2 {:?}", prop_value::<String>(&props.Property2, "state"));
+        },
+        Err(err) => { println!("Unexpected error: {}", err) }
+    }
+
+    let value = la.getProperty1().unwrap();
+    la.setProperty1(&(value + 1)).unwrap();
+    la.setProperty4(&vec!["a".to_string()]).unwrap();
+
+    let mut flags = la.getProperty7().unwrap();
+    for flag in flags.iter() { println!("Flag {:?}", flag); }
+
+    if ! flags.contains(Flags0::READ | Flags0::WRITE)
+    {
+        flags |= Flags0::READ;
+        la.setProperty7(&flags).unwrap();
+    }
+}
+
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("checks")
+    {
+        run_checks();
+        return;
+    }
+
     let connection = Connection::new_session().unwrap();
 
     let la = LA2::new(&connection, "Example.SystemService".to_string().into(), None);
@@ -59,6 +326,104 @@ fn main() {
                 eprintln!("Usage {} booking <booking ref>", argv_0);
             }
         }
+        else if arg == "options"
+        {
+            if let Some(method_arg) = cmd_line_args.next()
+            {
+                let la1 = LA::new(&connection, "Example.SystemService".to_string().into(), None);
+                call_method_18(&la1, &method_arg);
+            }
+            else
+            {
+                eprintln!("Usage {} options <arg>", argv_0);
+            }
+        }
+        else if arg == "status"
+        {
+            if let Some(method_arg) = cmd_line_args.next()
+            {
+                let la1 = LA::new(&connection, "Example.SystemService".to_string().into(), None);
+                call_method_21(&la1, &method_arg);
+            }
+            else
+            {
+                eprintln!("Usage {} status <arg>", argv_0);
+            }
+        }
+        else if arg == "points"
+        {
+            if let Some(name) = cmd_line_args.next()
+            {
+                let la1 = LA::new(&connection, "Example.SystemService".to_string().into(), None);
+                call_method_16(&la1, &name);
+            }
+            else
+            {
+                eprintln!("Usage {} points <name>", argv_0);
+            }
+        }
+        else if arg == "properties"
+        {
+            let default_connection = SystemService::default_connection().unwrap();
+            let la1 = LA::connect_default(&default_connection);
+            show_properties(&la1);
+        }
+        else if arg == "cancel"
+        {
+            if let (Some(booking_id), Some(reason)) = (cmd_line_args.next(), cmd_line_args.next())
+            {
+                let booking = BookingInterface::for_booking(&connection, &booking_id);
+                cancel_booking(&booking, &reason);
+            }
+            else
+            {
+                eprintln!("Usage {} cancel <booking id> <reason>", argv_0);
+            }
+        }
+        else if arg == "expired"
+        {
+            // Errors are generated from the errors files even if no method returns them
+            println!("{}: {}", BookingExpired::DBUS_NAME, BookingExpired::default());
+        }
+        else if arg == "errors"
+        {
+            check_error_args();
+        }
+        else if arg == "refcount"
+        {
+            check_ref_counts();
+        }
+        else if arg == "structs"
+        {
+            let la1 = LA::new(&connection, "Example.SystemService".to_string().into(), None);
+            call_method_24(&la1);
+        }
+        else if arg == "wait"
+        {
+            // The wait mustn't depend on getting the owner change before the listener
+            let listener = SystemService::listen_service_owner_changed(&connection, |changed| {
+                println!("Service started by {}", changed.new_owner);
+                true
+            }).unwrap();
+
+            match SystemService::wait_for_service(&connection, Duration::from_secs(10)) {
+                Ok(()) => println!("Service is running"),
+                Err(err) => println!("Service didn't start: {}", err)
+            }
+            SystemService::remove_service_owner_changed_listener(&connection, listener).unwrap();
+        }
+        else if arg == "upload"
+        {
+            if let Some(file_name) = cmd_line_args.next()
+            {
+                let la1 = LA::new(&connection, "Example.SystemService".to_string().into(), None);
+                call_method_19(&la1, &file_name);
+            }
+            else
+            {
+                eprintln!("Usage {} upload <file>", argv_0);
+            }
+        }
         else if arg == "listen"
         {
             la.listenSignal0(|changes: Signal0| {
@@ -81,6 +446,29 @@ fn main() {
                 true
             });
 
+            let la1 = LA::new(&connection, "Example.SystemService".to_string().into(), None);
+            la1.listenSignal5(|changes: Signal5| {
+                println!("Signal5: {} state {:?}", changes.arg, prop_value::<String>(&changes.arg1, "state"));
+                true
+            });
+
+            la1.listen_properties_changed(|changes: PropertiesChanged| {
+                for change in changes.changed {
+                    match change {
+                        ChangedProperty::Property0(value) => println!("Property0 is now {}", value),
+                        ChangedProperty::Property3(value) => println!("Property3 is now {:?}", value),
+                        other => println!("Changed: {:?}", other)
+                    }
+                }
+                println!("Invalidated: {:?}", changes.invalidated);
+                true
+            });
+
+            SystemService::listen_service_owner_changed(&connection, |changed| {
+                if changed.new_owner.is_empty() { println!("Service stopped"); } else { println!("Service started"); }
+                true
+            }).unwrap();
+
             loop { connection.process(Duration::from_millis(1000)).unwrap(); }
         }
     }